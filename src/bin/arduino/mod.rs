@@ -0,0 +1,324 @@
+//! Long lived connections to the arduinos.
+//!
+//! Each arduino is reached through a single connection, opened on the first
+//! command and kept open between commands. Commands are written in the order
+//! they are sent without waiting for the answers to the previous ones. The
+//! arduino answers each command with one line, so answers are matched to
+//! commands by their order.
+//!
+//! A command left unanswered past the reply timeout fails and the connection is
+//! dropped, as a late answer could not be told apart from the answer to the
+//! next command. Lines read while no command is waiting are discarded. Connections
+//! that can't be opened are retried with an exponential backoff, commands sent
+//! in the meantime fail at once.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+/// Commands waiting to be written to the connection
+const QUEUE: usize = 32;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+type Reply = oneshot::Sender<io::Result<String>>;
+
+struct Request {
+    line: String,
+    reply: Reply,
+}
+
+/// A command written to the arduino, waiting for its answer
+struct Pending {
+    sent: Instant,
+    reply: Reply,
+}
+
+/// What the reader waits for
+enum Next {
+    Command(Option<Pending>),
+    Line(Option<io::Result<String>>),
+}
+
+/// A handle on the connection to an arduino
+pub struct Connection {
+    /// Shared so that the commands waiting to be queued are bounded too
+    requests: Mutex<mpsc::Sender<Request>>,
+}
+
+impl Connection {
+    /// Starts the task managing the connection, it stops once the handle is dropped
+    pub fn open(address: String, connect_timeout: Duration, reply_timeout: Duration) -> Connection {
+        let (requests, receiver) = mpsc::channel(QUEUE);
+        tokio::spawn(manage(address, connect_timeout, reply_timeout, receiver));
+        Connection { requests: Mutex::new(requests) }
+    }
+
+    /// Sends a line and returns the line the arduino answered, trimmed
+    pub async fn send(&self, line: String) -> io::Result<String> {
+        let (reply, answer) = oneshot::channel();
+        if self.requests.lock().await.send(Request { line, reply }).await.is_err() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "the connection is closed"));
+        }
+        match answer.await {
+            Ok(answer) => answer,
+            Err(_) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the connection was lost")),
+        }
+    }
+}
+
+/// Writes the commands to the connection, reconnecting when it is lost
+async fn manage(address: String, connect_timeout: Duration, reply_timeout: Duration, mut requests: mpsc::Receiver<Request>) {
+    let mut connection = None;
+    let mut backoff = MIN_BACKOFF;
+    let mut retry_at = Instant::now();
+    while let Some(Request { line, mut reply }) = requests.recv().await {
+        let mut fresh = false;
+        loop {
+            if connection.is_none() {
+                if Instant::now() < retry_at {
+                    let _ = reply.send(Err(io::Error::new(io::ErrorKind::NotConnected, "waiting to reconnect")));
+                    break;
+                }
+                match connect(address.clone(), connect_timeout, reply_timeout).await {
+                    Ok(opened) => {
+                        tracing::debug!("Connected to arduino {}", address);
+                        connection = Some(opened);
+                        backoff = MIN_BACKOFF;
+                        fresh = true;
+                    }
+                    Err(e) => {
+                        tracing::debug!("Could not connect to arduino {}, retrying in {:?}: {}", address, backoff, e);
+                        retry_at = Instant::now() + backoff;
+                        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                        let _ = reply.send(Err(e));
+                        break;
+                    }
+                }
+            }
+            let (writer, pending) = match &mut connection {
+                Some(connection) => connection,
+                None => break,
+            };
+            // Queued before writing so that the answer can't come before its command is known
+            match pending.try_send(Pending { sent: Instant::now(), reply }) {
+                Ok(()) => {
+                    if let Err(e) = writer.write_all(line.as_bytes()).await {
+                        // The reader fails too and answers the command
+                        tracing::debug!("Lost the connection to arduino {}: {}", address, e);
+                        connection = None;
+                    }
+                    break;
+                }
+                Err(e) => {
+                    // The reader stopped since the last command, a new connection is tried once
+                    reply = e.into_inner().reply;
+                    connection = None;
+                    if fresh {
+                        let _ = reply.send(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the connection was lost")));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Opens a connection, its answers are read by a separate task
+async fn connect(
+    address: String,
+    connect_timeout: Duration,
+    reply_timeout: Duration,
+) -> io::Result<(impl AsyncWrite + Unpin, mpsc::UnboundedSender<Pending>)> {
+    let stream = match tokio::timer::Timeout::new(TcpStream::connect(&address), connect_timeout).await {
+        Ok(stream) => stream?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "the arduino did not accept the connection")),
+    };
+    let (reader, writer) = io::split(stream);
+    let (pending, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(read_answers(reader, reply_timeout, receiver));
+    Ok((writer, pending))
+}
+
+/// Gives each line read to the oldest command waiting for an answer, until the
+/// connection fails
+async fn read_answers(reader: impl AsyncRead + Unpin, reply_timeout: Duration, mut pending: mpsc::UnboundedReceiver<Pending>) {
+    let mut lines = io::BufReader::new(reader).lines();
+    let mut waiting = VecDeque::new();
+    let mut writing = true;
+    let failure = loop {
+        let deadline = waiting.front().map(|command: &Pending| command.sent + reply_timeout);
+        let next = futures::future::poll_fn(|cx| {
+            // A command is queued before it is written, so it is known before its answer is read
+            if writing {
+                if let Poll::Ready(command) = pending.poll_recv(cx) {
+                    return Poll::Ready(Next::Command(command));
+                }
+            }
+            Pin::new(&mut lines).poll_next(cx).map(Next::Line)
+        });
+        let next = match deadline {
+            Some(deadline) => match tokio::timer::Timeout::new_at(next, deadline).await {
+                Ok(next) => next,
+                Err(_) => {
+                    tracing::debug!("Arduino did not answer to command");
+                    break io::Error::new(io::ErrorKind::TimedOut, "the arduino did not answer");
+                }
+            },
+            None => next.await,
+        };
+        match next {
+            Next::Command(Some(command)) => waiting.push_back(command),
+            // The connection was dropped, only the answers to the commands written are still read
+            Next::Command(None) => writing = false,
+            Next::Line(Some(Ok(line))) => match waiting.pop_front() {
+                Some(command) => {
+                    let _ = command.reply.send(Ok(line.trim().to_owned()));
+                }
+                None => tracing::debug!("Discarded a line the arduino sent unasked: {:?}", line),
+            },
+            Next::Line(Some(Err(e))) => break e,
+            Next::Line(None) => break io::Error::new(io::ErrorKind::UnexpectedEof, "the arduino closed the connection"),
+        }
+        if !writing && waiting.is_empty() {
+            return;
+        }
+    };
+    if let Some(command) = waiting.pop_front() {
+        let _ = command.reply.send(Err(failure));
+    }
+    // The commands already written will never get their answer
+    pending.close();
+    while let Some(command) = pending.recv().await {
+        waiting.push_back(command);
+    }
+    for command in waiting {
+        let _ = command.reply.send(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the connection was lost")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::timer::delay_for;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    async fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        (listener, address)
+    }
+
+    /// Reads the next `count` commands written to the fake arduino
+    async fn read_lines(stream: &mut io::BufReader<TcpStream>, count: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            lines.push(line.trim().to_owned());
+        }
+        lines
+    }
+
+    async fn accept(listener: &mut TcpListener) -> io::BufReader<TcpStream> {
+        io::BufReader::new(listener.accept().await.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn pipelined_answers() {
+        let (mut listener, address) = listen().await;
+        tokio::spawn(async move {
+            let mut stream = accept(&mut listener).await;
+            // Answers once every command was written, in order
+            let lines = read_lines(&mut stream, 3).await;
+            for line in lines {
+                stream.get_mut().write_all(format!("got {}\n", line).as_bytes()).await.unwrap();
+            }
+            // Sent while no command waits, then answers the next one
+            stream.get_mut().write_all(b"stray\n\n").await.unwrap();
+            let lines = read_lines(&mut stream, 1).await;
+            stream.get_mut().write_all(format!("got {}\n", lines[0]).as_bytes()).await.unwrap();
+            read_lines(&mut stream, 1).await;
+        });
+        let connection = Connection::open(address, TIMEOUT, TIMEOUT);
+        let answers = futures::future::join_all(vec![
+            connection.send("a\n".to_owned()),
+            connection.send("b\n".to_owned()),
+            connection.send("c\n".to_owned()),
+        ]).await;
+        let answers: Vec<String> = answers.into_iter().map(Result::unwrap).collect();
+        assert_eq!(answers, vec!["got a", "got b", "got c"]);
+        delay_for(Duration::from_millis(50)).await;
+        assert_eq!(connection.send("d\n".to_owned()).await.unwrap(), "got d");
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_drop() {
+        let (mut listener, address) = listen().await;
+        let (dropped, closed) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut stream = accept(&mut listener).await;
+            read_lines(&mut stream, 1).await;
+            stream.get_mut().write_all(b"first\n").await.unwrap();
+            drop(stream);
+            let _ = dropped.send(());
+            let mut stream = accept(&mut listener).await;
+            read_lines(&mut stream, 1).await;
+            stream.get_mut().write_all(b"second\n").await.unwrap();
+            read_lines(&mut stream, 1).await;
+        });
+        let connection = Connection::open(address, TIMEOUT, TIMEOUT);
+        assert_eq!(connection.send("a\n".to_owned()).await.unwrap(), "first");
+        closed.await.unwrap();
+        delay_for(Duration::from_millis(50)).await;
+        assert_eq!(connection.send("b\n".to_owned()).await.unwrap(), "second");
+    }
+
+    #[tokio::test]
+    async fn backoff_window() {
+        // Nothing listens on the port once the listener is dropped
+        let address = listen().await.1;
+        let connection = Connection::open(address, TIMEOUT, TIMEOUT);
+        let refused = connection.send("a\n".to_owned()).await.unwrap_err();
+        assert_ne!(refused.kind(), io::ErrorKind::NotConnected);
+        // Not retried before the backoff elapsed
+        let waiting = connection.send("b\n".to_owned()).await.unwrap_err();
+        assert_eq!(waiting.kind(), io::ErrorKind::NotConnected);
+        delay_for(MIN_BACKOFF + Duration::from_millis(50)).await;
+        let retried = connection.send("c\n".to_owned()).await.unwrap_err();
+        assert_ne!(retried.kind(), io::ErrorKind::NotConnected);
+        // The backoff doubled
+        delay_for(MIN_BACKOFF + Duration::from_millis(50)).await;
+        let waiting = connection.send("d\n".to_owned()).await.unwrap_err();
+        assert_eq!(waiting.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[tokio::test]
+    async fn reply_timeout() {
+        let (mut listener, address) = listen().await;
+        tokio::spawn(async move {
+            let mut stream = accept(&mut listener).await;
+            read_lines(&mut stream, 1).await;
+            // An answer without its line feed is not an answer
+            stream.get_mut().write_all(b"partial").await.unwrap();
+            let mut stream = accept(&mut listener).await;
+            read_lines(&mut stream, 1).await;
+            stream.get_mut().write_all(b"ok\n").await.unwrap();
+            read_lines(&mut stream, 1).await;
+        });
+        let connection = Connection::open(address, TIMEOUT, Duration::from_millis(100));
+        let sent = Instant::now();
+        let error = connection.send("a\n".to_owned()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(sent.elapsed() >= Duration::from_millis(100));
+        // The late connection was dropped and a new one is opened
+        assert_eq!(connection.send("b\n".to_owned()).await.unwrap(), "ok");
+    }
+}
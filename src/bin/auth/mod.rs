@@ -0,0 +1,187 @@
+//! Token authentication of the RPCs.
+//!
+//! Clients send `authorization: Bearer <token>` with each request. Only a hash
+//! of each token is stored, so tokens are shown once when created and can not
+//! be recovered afterwards.
+//!
+//! Every request is refused until a token exists. The first admin token is
+//! created offline with `server --create-admin-token <name>`, which writes it
+//! to the token store without starting the server.
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tonic::Status;
+
+use crate::migrations;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Role {
+    /// Lists devices, watches their state and reads sensors
+    ReadOnly,
+    /// Commands devices and manages groups, scenes, schedules and rules
+    Operator,
+    /// Everything, including devices, actionners and tokens
+    Admin,
+}
+
+impl Role {
+    /// The role needed to call an RPC, given the path of the request
+    pub fn required(path: &str) -> Role {
+        match path.rsplit('/').next().unwrap_or("") {
+            "ListDevice" | "GetDeviceState" | "WatchEvents" | "GetReadings" | "ListGroups" | "ListScenes"
+            | "ListSchedules" | "ListRules" | "EvaluateRules" | "ListActionner" | "ListProtocol" => Role::ReadOnly,
+            "Command" | "GroupCommand" | "ActivateScene" | "RecordReading" | "CreateGroup" | "DeleteGroup"
+            | "AddToGroup" | "RemoveFromGroup" | "CreateScene" | "DeleteScene" | "CreateSchedule"
+            | "DeleteSchedule" | "CreateRule" | "DeleteRule" => Role::Operator,
+            // New RPCs are restricted until they are listed above
+            _ => Role::Admin,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub name: String,
+    pub role: Role,
+    /// Unix timestamp of the creation of the token
+    pub created: u64,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Sled(sled::Error),
+    Serde(bincode::Error),
+    Migration(migrations::MigrationError),
+    NotFound,
+    AlreadyExists,
+}
+impl From<sled::Error> for AuthError {
+    fn from(err: sled::Error) -> Self {
+        Self::Sled(err)
+    }
+}
+impl From<bincode::Error> for AuthError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Serde(err)
+    }
+}
+impl From<migrations::MigrationError> for AuthError {
+    fn from(err: migrations::MigrationError) -> Self {
+        Self::Migration(err)
+    }
+}
+
+fn hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Tokens indexed by their hash
+#[derive(Clone)]
+pub struct Tokens {
+    tokens: sled::Tree,
+}
+
+impl Tokens {
+    pub fn open(mut data_dir: std::path::PathBuf) -> Result<Tokens, AuthError> {
+        data_dir.push("auth");
+        let db = sled::Db::open(data_dir)?;
+        migrations::AUTH.upgrade(&db)?;
+        Ok(Tokens {
+            tokens: db.open_tree("tokens")?,
+        })
+    }
+
+    pub fn list(&self) -> Result<Vec<TokenInfo>, AuthError> {
+        let mut list = Vec::new();
+        for entry in self.tokens.iter() {
+            let (_, info) = entry?;
+            list.push(bincode::deserialize(&info)?);
+        }
+        Ok(list)
+    }
+
+    /// Returns the new token, the only time it can be read
+    pub fn create(&self, name: &str, role: Role) -> Result<String, AuthError> {
+        if self.list()?.iter().any(|info| info.name == name) {
+            return Err(AuthError::AlreadyExists);
+        }
+        let mut secret = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut secret);
+        let token: String = secret.iter().map(|b| format!("{:02x}", b)).collect();
+        let info = TokenInfo {
+            name: name.to_owned(),
+            role,
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        self.tokens.insert(hash(&token), bincode::serialize(&info)?)?;
+        Ok(token)
+    }
+
+    pub fn revoke(&self, name: &str) -> Result<(), AuthError> {
+        for entry in self.tokens.iter() {
+            let (key, info) = entry?;
+            let info: TokenInfo = bincode::deserialize(&info)?;
+            if info.name == name {
+                self.tokens.remove(key)?;
+                return Ok(());
+            }
+        }
+        Err(AuthError::NotFound)
+    }
+
+    /// Checks that the `authorization` header allows the RPC at `path`, every
+    /// call is refused while no token exists
+    pub fn check(&self, header: Option<&http::HeaderValue>, path: &str) -> Result<(), Status> {
+        let internal = |e| {
+            tracing::warn!("Internal error checking token: {:?}", e);
+            Status::new(tonic::Code::Internal, "")
+        };
+        let token = match header.and_then(|h| h.to_str().ok()) {
+            Some(h) if h.starts_with("Bearer ") => &h["Bearer ".len()..],
+            _ => return Err(Status::new(tonic::Code::Unauthenticated, "missing token")),
+        };
+        let info: TokenInfo = match self.tokens.get(hash(token)).map_err(|e| internal(AuthError::from(e)))? {
+            Some(info) => bincode::deserialize(&info).map_err(|e| internal(AuthError::from(e)))?,
+            None => return Err(Status::new(tonic::Code::Unauthenticated, "invalid token")),
+        };
+        if info.role < Role::required(path) {
+            return Err(Status::new(tonic::Code::PermissionDenied, "the token does not allow this call"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(token: &str) -> http::HeaderValue {
+        http::HeaderValue::from_str(&format!("Bearer {}", token)).unwrap()
+    }
+
+    #[test]
+    fn no_token_refuses_every_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = Tokens::open(dir.path().to_owned()).unwrap();
+        let status = tokens.check(None, "/home_manager.HomeManager/ListDevice").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let status = tokens.check(Some(&bearer("guess")), "/home_manager.HomeManager/CreateToken").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn roles_are_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = Tokens::open(dir.path().to_owned()).unwrap();
+        let reader = tokens.create("reader", Role::ReadOnly).unwrap();
+        assert!(tokens.check(Some(&bearer(&reader)), "/home_manager.HomeManager/ListDevice").is_ok());
+        let status = tokens.check(Some(&bearer(&reader)), "/home_manager.HomeManager/Command").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        tokens.revoke("reader").unwrap();
+        assert!(tokens.check(Some(&bearer(&reader)), "/home_manager.HomeManager/ListDevice").is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::events::EventBus;
+use crate::objects::{DeviceState, Object, ObjectKind, Protocol};
+use crate::{ActionnerData, ActionnerError, Actionners, Connected, DeviceBatch, DeviceError, Devices};
+
+/// Version of the document written by `export`
+pub const VERSION: u32 = 1;
+
+/// Full content of the database.
+///
+/// Everything is stored using its textual representation so that the document
+/// does not depend on the layout of the structures stored in sled.
+#[derive(Serialize, Deserialize)]
+pub struct Document {
+    pub version: u32,
+    pub actionners: Vec<ExportedActionner>,
+    pub devices: Vec<ExportedDevice>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct ExportedActionner {
+    pub id: u32,
+    pub name: String,
+    pub protocol: String,
+    pub remote: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct ExportedDevice {
+    pub id: u32,
+    pub name: String,
+    pub kind: String,
+    pub actionner_id: u32,
+    pub id_in_actionner: String,
+    pub state: String,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    Json(serde_json::Error),
+    UnsupportedVersion(u32),
+    Actionners(ActionnerError),
+    Devices(DeviceError),
+}
+impl From<serde_json::Error> for BackupError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+impl From<ActionnerError> for BackupError {
+    fn from(err: ActionnerError) -> Self {
+        Self::Actionners(err)
+    }
+}
+impl From<DeviceError> for BackupError {
+    fn from(err: DeviceError) -> Self {
+        Self::Devices(err)
+    }
+}
+
+fn state_name(state: DeviceState) -> String {
+    match state {
+        DeviceState::Unknown => "unknown",
+        DeviceState::On => "on",
+        DeviceState::Off => "off",
+    }
+    .to_owned()
+}
+
+fn parse_state(state: &str) -> Option<DeviceState> {
+    match state {
+        "unknown" => Some(DeviceState::Unknown),
+        "on" => Some(DeviceState::On),
+        "off" => Some(DeviceState::Off),
+        _ => None,
+    }
+}
+
+fn exported_device(id: u32, obj: &Object, state: DeviceState) -> ExportedDevice {
+    ExportedDevice {
+        id,
+        name: obj.name.clone(),
+        kind: obj.kind.name(),
+        actionner_id: obj.actionner_id,
+        id_in_actionner: obj.id_in_actionner.repr(),
+        state: state_name(state),
+    }
+}
+
+pub fn export(devices: &Devices, actionners: &Actionners) -> Result<Document, BackupError> {
+    let mut exported_actionners: Vec<_> = actionners
+        .get_data()
+        .into_iter()
+        .map(|(id, data)| ExportedActionner {
+            id,
+            name: data.name,
+            protocol: data.protocol.name(),
+            remote: data.remote,
+        })
+        .collect();
+    exported_actionners.sort_by_key(|a| a.id);
+    let mut exported_devices = Vec::new();
+    for (id, obj) in devices.list()? {
+        exported_devices.push(exported_device(id, &obj, devices.state(id)?));
+    }
+    exported_devices.sort_by_key(|d| d.id);
+    Ok(Document {
+        version: VERSION,
+        actionners: exported_actionners,
+        devices: exported_devices,
+    })
+}
+
+impl Document {
+    pub fn parse(document: &str) -> Result<Document, BackupError> {
+        let document: Document = serde_json::from_str(document)?;
+        if document.version != VERSION {
+            return Err(BackupError::UnsupportedVersion(document.version));
+        }
+        Ok(document)
+    }
+
+    /// Lists the problems preventing the import, a conflict is an id already
+    /// used by a different entry.
+    ///
+    /// Conflicts are ignored if `overwrite` is set, invalid entries never are.
+    pub fn check(&self, devices: &Devices, actionners: &Actionners, overwrite: bool) -> Result<Vec<String>, BackupError> {
+        let mut problems = Vec::new();
+        let existing: HashMap<u32, ActionnerData> = actionners.get_data().into_iter().collect();
+        let mut protocols = HashMap::new();
+        let mut actionner_ids = HashSet::new();
+        for actionner in &self.actionners {
+            if !actionner_ids.insert(actionner.id) {
+                problems.push(format!("actionner {}: duplicate id", actionner.id));
+            }
+            let protocol: Protocol = match actionner.protocol.parse() {
+                Ok(p) => p,
+                Err(_) => {
+                    problems.push(format!("actionner {}: unknown protocol {}", actionner.id, actionner.protocol));
+                    continue;
+                }
+            };
+            protocols.insert(actionner.id, protocol);
+            match existing.get(&actionner.id) {
+                Some(data) if !overwrite && (data.name != actionner.name || data.protocol != protocol || data.remote != actionner.remote) => {
+                    problems.push(format!("actionner {}: conflicts with {}", actionner.id, data.name))
+                }
+                _ => (),
+            }
+        }
+        let mut device_ids = HashSet::new();
+        for device in &self.devices {
+            if !device_ids.insert(device.id) {
+                problems.push(format!("device {}: duplicate id", device.id));
+            }
+            let kind = device.kind.parse::<ObjectKind>().ok();
+            if kind.is_none() {
+                problems.push(format!("device {}: unknown kind {}", device.id, device.kind));
+            }
+            if parse_state(&device.state).is_none() {
+                problems.push(format!("device {}: unknown state {}", device.id, device.state));
+            }
+            let protocol = match protocols.get(&device.actionner_id).copied().or_else(|| actionners.protocol(device.actionner_id)) {
+                Some(p) => p,
+                None => {
+                    problems.push(format!("device {}: unknown actionner {}", device.id, device.actionner_id));
+                    continue;
+                }
+            };
+            if protocol.parse_id(&device.id_in_actionner).is_err() {
+                problems.push(format!("device {}: invalid id {}", device.id, device.id_in_actionner));
+            }
+            match kind {
+                Some(kind) if !protocol.drives(&kind) => {
+                    problems.push(format!("device {}: actionner {} can't drive {}", device.id, device.actionner_id, device.kind))
+                }
+                _ => (),
+            }
+            if overwrite {
+                continue;
+            }
+            if let Some(obj) = devices.get(device.id)? {
+                let current = exported_device(device.id, &obj, devices.state(device.id)?);
+                if current.name != device.name
+                    || current.kind != device.kind
+                    || current.actionner_id != device.actionner_id
+                    || current.id_in_actionner != device.id_in_actionner
+                {
+                    problems.push(format!("device {}: conflicts with {}", device.id, obj.name));
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// The actionners of the document, for `Actionners::connect_new`
+    pub fn actionner_data(&self) -> Vec<ActionnerData> {
+        self.actionners
+            .iter()
+            .filter_map(|actionner| {
+                Some(ActionnerData {
+                    protocol: actionner.protocol.parse().ok()?,
+                    remote: actionner.remote.clone(),
+                    name: actionner.name.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the document in the database keeping the ids, `check` must have passed.
+    ///
+    /// The actionners and then the devices are each written in a single transaction
+    pub fn import(&self, devices: &mut Devices, actionners: &mut Actionners, connected: &mut Connected, events: &EventBus) -> Result<(), BackupError> {
+        let current: HashMap<u32, ActionnerData> = actionners.get_data().into_iter().collect();
+        let mut changes = Vec::new();
+        for actionner in &self.actionners {
+            let data = ActionnerData {
+                protocol: actionner.protocol.parse().unwrap(),
+                remote: actionner.remote.clone(),
+                name: actionner.name.clone(),
+            };
+            match current.get(&actionner.id) {
+                Some(old) if old.name == data.name && old.protocol == data.protocol && old.remote == data.remote => (),
+                _ => changes.push((actionner.id, Some(data))),
+            }
+        }
+        actionners.write_all(changes, connected)?;
+        let mut batch = DeviceBatch::default();
+        for device in &self.devices {
+            let protocol = actionners.protocol(device.actionner_id).unwrap();
+            let obj = Object {
+                name: device.name.clone(),
+                kind: device.kind.parse().unwrap(),
+                actionner_id: device.actionner_id,
+                id_in_actionner: protocol.parse_id(&device.id_in_actionner).unwrap(),
+            };
+            batch.put.push((device.id, obj, false));
+            batch.states.push((device.id, parse_state(&device.state).unwrap()));
+        }
+        devices.apply(batch)?.publish(devices, events)?;
+        Ok(())
+    }
+}
@@ -1,10 +1,20 @@
 use serde::{Deserialize, Serialize};
 
+use crate::objects::ObjectKind;
+
 #[derive(Serialize, Deserialize)]
 pub enum ArduinoCommand {
     Set { state: bool },
     Toggle,
     Check,
+    /// Asks the arduino for the current state of a pin
+    Query,
+    /// Brightness in percent, 0 turns the light off
+    SetBrightness { level: u8 },
+    /// Black turns the light off
+    SetColor { r: u8, g: u8, b: u8 },
+    /// Asks a sensor for its current value
+    Read,
 }
 
 impl ArduinoCommand {
@@ -14,6 +24,31 @@ impl ArduinoCommand {
             ArduinoCommand::Set { state: false } => format!("off {}\n", id),
             ArduinoCommand::Toggle => format!("tog {}\n", id),
             ArduinoCommand::Check => format!("ard\n"),
+            ArduinoCommand::Query => format!("get {}\n", id),
+            ArduinoCommand::SetBrightness { level } => format!("dim {} {}\n", id, level),
+            ArduinoCommand::SetColor { r, g, b } => format!("rgb {} {} {} {}\n", id, r, g, b),
+            ArduinoCommand::Read => format!("read {}\n", id),
+        }
+    }
+
+    /// Checks that the command makes sense for a kind of device
+    pub fn validate(&self, kind: &ObjectKind) -> Result<(), &'static str> {
+        match (self, kind) {
+            (ArduinoCommand::Read, kind) if kind.is_sensor() => Ok(()),
+            (ArduinoCommand::Read, _) => Err("the device is not a sensor"),
+            (ArduinoCommand::Check, _) | (ArduinoCommand::Query, _) => Ok(()),
+            (_, kind) if kind.is_sensor() => Err("sensors can only be read"),
+            (ArduinoCommand::SetBrightness { level }, _) if *level > 100 => Err("brightness is a percentage"),
+            (ArduinoCommand::SetBrightness { .. }, ObjectKind::LED) => Err("the device can't be dimmed"),
+            (ArduinoCommand::SetColor { .. }, ObjectKind::RgbLight) => Ok(()),
+            (ArduinoCommand::SetColor { .. }, _) => Err("the device has no color"),
+            _ => Ok(()),
         }
     }
 }
+
+#[derive(Serialize, Deserialize)]
+pub enum SshCommand {
+    /// Runs the device script with the given arguments
+    Run { args: Vec<String> },
+}
@@ -0,0 +1,273 @@
+use serde::Deserialize;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(name = "server", about = "The home manager server")]
+pub struct Args {
+    #[structopt(help = "the configuration file", long, short, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    #[structopt(help = "address to listen on, can be repeated", long, short)]
+    pub listen: Vec<SocketAddr>,
+    #[structopt(help = "the directory holding the database", long, short, parse(from_os_str))]
+    pub data_dir: Option<PathBuf>,
+    #[structopt(help = "the maximum log level (error, warn, info, debug, trace)", long)]
+    pub log_level: Option<LogLevel>,
+    #[structopt(help = "the home manifest to apply", long, short, parse(from_os_str))]
+    pub manifest: Option<PathBuf>,
+    #[structopt(help = "create an admin token with this name, print it and exit", long)]
+    pub create_admin_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub data_dir: Option<PathBuf>,
+    /// Home manifest applied on start and on reload
+    pub manifest: Option<PathBuf>,
+    /// Seconds between two health checks of the actionners, at least 1
+    pub health_interval: u64,
+    pub log: LogConfig,
+    /// Plaintext HTTP/2 is served if absent
+    pub tls: Option<TlsConfig>,
+    pub arduino: ArduinoConfig,
+    pub ssh: SshConfig,
+    pub readings: ReadingsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec!["[::1]:14563".parse().unwrap()],
+            data_dir: None,
+            manifest: None,
+            health_interval: 30,
+            log: LogConfig::default(),
+            tls: None,
+            arduino: ArduinoConfig::default(),
+            ssh: SshConfig::default(),
+            readings: ReadingsConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain of the server
+    pub cert: PathBuf,
+    /// PEM private key of the server
+    pub key: PathBuf,
+    /// PEM certificates of the authorities signing client certificates.
+    /// Clients must present a certificate if set
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+impl Default for LogLevel {
+    fn default() -> Self {
+        LogLevel::Debug
+    }
+}
+impl LogLevel {
+    pub fn level(self) -> tracing::Level {
+        match self {
+            LogLevel::Error => tracing::Level::ERROR,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Trace => tracing::Level::TRACE,
+        }
+    }
+}
+impl std::str::FromStr for LogLevel {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err("unknown log level"),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Full,
+    Compact,
+}
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Full
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArduinoConfig {
+    /// Milliseconds to wait for the arduino to accept a connection
+    pub check_timeout: u64,
+    /// Milliseconds to wait for the answer to a command
+    pub reply_timeout: u64,
+}
+impl Default for ArduinoConfig {
+    fn default() -> Self {
+        ArduinoConfig {
+            check_timeout: 100,
+            reply_timeout: 500,
+        }
+    }
+}
+impl ArduinoConfig {
+    pub fn check_timeout(&self) -> Duration {
+        Duration::from_millis(self.check_timeout)
+    }
+    pub fn reply_timeout(&self) -> Duration {
+        Duration::from_millis(self.reply_timeout)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshConfig {
+    /// The ssh client to run
+    pub program: String,
+    /// Private key used to authenticate, the ssh defaults are used if absent
+    pub identity: Option<PathBuf>,
+    /// Seconds to wait for the connection to the host
+    pub connect_timeout: u64,
+}
+impl Default for SshConfig {
+    fn default() -> Self {
+        SshConfig {
+            program: "ssh".to_owned(),
+            identity: None,
+            connect_timeout: 5,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadingsConfig {
+    /// Seconds between two readings of every sensor, 0 to only record the readings sent to the server
+    pub poll_interval: u64,
+    /// Days the readings are kept, at least 1
+    pub retention: u64,
+}
+impl Default for ReadingsConfig {
+    fn default() -> Self {
+        ReadingsConfig {
+            poll_interval: 60,
+            retention: 30,
+        }
+    }
+}
+
+/// Settings given to the handlers of each protocol
+#[derive(Clone, Default)]
+pub struct ProtocolSettings {
+    pub arduino: ArduinoConfig,
+    pub ssh: SshConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Invalid(&'static str),
+}
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        Self::Toml(err)
+    }
+}
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "could not read config: {}", e),
+            ConfigError::Toml(e) => write!(f, "invalid config: {}", e),
+            ConfigError::Invalid(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the configuration file and applies the command line overrides.
+    ///
+    /// Without `--config` the file is looked up in the user configuration
+    /// directory, and the defaults are used if it does not exist.
+    pub fn load(args: Args) -> Result<Config, ConfigError> {
+        let mut config = match args.config {
+            Some(path) => toml::from_str(&std::fs::read_to_string(path)?)?,
+            None => match dirs::config_dir().map(|d| d.join("home_manager").join("server.toml")) {
+                Some(path) if path.exists() => toml::from_str(&std::fs::read_to_string(path)?)?,
+                _ => Config::default(),
+            },
+        };
+        if !args.listen.is_empty() {
+            config.listen = args.listen;
+        }
+        if args.data_dir.is_some() {
+            config.data_dir = args.data_dir;
+        }
+        if args.manifest.is_some() {
+            config.manifest = args.manifest;
+        }
+        if let Some(level) = args.log_level {
+            config.log.level = level;
+        }
+        if config.health_interval == 0 {
+            return Err(ConfigError::Invalid("health_interval can't be 0"));
+        }
+        if config.readings.retention == 0 {
+            return Err(ConfigError::Invalid("readings.retention can't be 0"));
+        }
+        Ok(config)
+    }
+    pub fn data_dir(&self) -> PathBuf {
+        match &self.data_dir {
+            Some(d) => d.clone(),
+            None => {
+                let mut data_dir = dirs::data_dir().expect("did not find data dir");
+                data_dir.push("home_manager");
+                data_dir
+            }
+        }
+    }
+    pub fn health_interval(&self) -> Duration {
+        Duration::from_secs(self.health_interval)
+    }
+    pub fn protocol_settings(&self) -> ProtocolSettings {
+        ProtocolSettings {
+            arduino: self.arduino.clone(),
+            ssh: self.ssh.clone(),
+        }
+    }
+}
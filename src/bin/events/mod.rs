@@ -0,0 +1,106 @@
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::objects::{DeviceState, Object};
+use crate::rules::Budget;
+
+#[derive(Clone, Debug)]
+pub enum EventKind {
+    /// The last known state of a device changed
+    StateChanged { object_id: u32, state: DeviceState },
+    /// A command was sent to a device, whatever its outcome
+    CommandExecuted { object_id: u32, success: bool, state: DeviceState },
+    DeviceRegistered { object_id: u32 },
+    DeviceRemoved { object_id: u32 },
+    /// The name, kind or actionner of a device changed
+    DeviceUpdated { object_id: u32 },
+    ActionnerOnline { actionner_id: u32 },
+    ActionnerOffline { actionner_id: u32 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub kind: EventKind,
+    /// Kind of the device the event is about, 0 for actionner events
+    pub kind_id: u32,
+    /// Actionner of the device the event is about, or the actionner itself
+    pub actionner_id: u32,
+    /// Number of rules that fired in a row to cause this event, 0 if it did
+    /// not come from a rule
+    pub depth: u32,
+    /// Rule actions left to the rules fired since the root event, `None` for
+    /// a root event
+    pub budget: Option<Budget>,
+}
+
+impl Event {
+    pub fn device(kind: EventKind, object: &Object, depth: u32) -> Event {
+        Event {
+            kind,
+            kind_id: object.kind.id(),
+            actionner_id: object.actionner_id,
+            depth,
+            budget: None,
+        }
+    }
+
+    pub fn actionner(kind: EventKind, actionner_id: u32) -> Event {
+        Event {
+            kind,
+            kind_id: 0,
+            actionner_id,
+            depth: 0,
+            budget: None,
+        }
+    }
+
+    /// The same event, caused by rules sharing `budget`
+    pub fn with_budget(self, budget: Option<Budget>) -> Event {
+        Event { budget, ..self }
+    }
+
+    /// The device the event is about, if any
+    pub fn object_id(&self) -> Option<u32> {
+        match self.kind {
+            EventKind::StateChanged { object_id, .. }
+            | EventKind::CommandExecuted { object_id, .. }
+            | EventKind::DeviceRegistered { object_id }
+            | EventKind::DeviceRemoved { object_id }
+            | EventKind::DeviceUpdated { object_id } => Some(object_id),
+            EventKind::ActionnerOnline { .. } | EventKind::ActionnerOffline { .. } => None,
+        }
+    }
+}
+
+/// Delivers the events of the server to every subscriber.
+///
+/// Publishing never waits: a subscriber whose queue is full misses the event.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<std::sync::Mutex<Vec<mpsc::Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self, capacity: usize) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let mut open = Vec::with_capacity(subscribers.len());
+        for mut subscriber in subscribers.drain(..) {
+            match subscriber.try_send(event.clone()) {
+                Ok(()) => open.push(subscriber),
+                Err(e) if e.is_full() => {
+                    tracing::warn!("Dropped event {:?} for a slow subscriber", event.kind);
+                    open.push(subscriber);
+                }
+                // The subscriber is gone
+                Err(_) => (),
+            }
+        }
+        *subscribers = open;
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeSet;
+
+use crate::Write;
+
+/// Named sets of devices, stored next to the devices.
+///
+/// A device can belong to any number of groups. Groups may reference devices
+/// that were removed meanwhile, users of `members` skip them.
+pub struct Groups {
+    groups: sled::Tree,
+}
+
+#[derive(Debug)]
+pub enum GroupError {
+    Sled(sled::Error),
+    Serde(bincode::Error),
+    NotFound,
+    AlreadyExists,
+}
+impl From<sled::Error> for GroupError {
+    fn from(err: sled::Error) -> Self {
+        Self::Sled(err)
+    }
+}
+impl From<bincode::Error> for GroupError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Serde(err)
+    }
+}
+
+impl Groups {
+    pub fn open(db: &sled::Db) -> Result<Groups, GroupError> {
+        Ok(Groups {
+            groups: db.open_tree("groups")?,
+        })
+    }
+
+    pub fn create(&self, name: &str) -> Result<(), GroupError> {
+        let empty = bincode::serialize(&BTreeSet::<u32>::new())?;
+        match self.groups.cas(name.as_bytes(), None as Option<&[u8]>, Some(empty))? {
+            Ok(()) => Ok(()),
+            Err(_) => Err(GroupError::AlreadyExists),
+        }
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), GroupError> {
+        match self.groups.remove(name.as_bytes())? {
+            Some(_) => Ok(()),
+            None => Err(GroupError::NotFound),
+        }
+    }
+
+    pub fn members(&self, name: &str) -> Result<BTreeSet<u32>, GroupError> {
+        match self.groups.get(name.as_bytes())? {
+            Some(m) => Ok(bincode::deserialize(&m)?),
+            None => Err(GroupError::NotFound),
+        }
+    }
+
+    fn set_members(&self, name: &str, members: &BTreeSet<u32>) -> Result<(), GroupError> {
+        self.groups.insert(name.as_bytes(), bincode::serialize(members)?)?;
+        Ok(())
+    }
+
+    pub fn add(&self, name: &str, device: u32) -> Result<(), GroupError> {
+        let mut members = self.members(name)?;
+        members.insert(device);
+        self.set_members(name, &members)
+    }
+
+    pub fn remove(&self, name: &str, device: u32) -> Result<(), GroupError> {
+        let mut members = self.members(name)?;
+        members.remove(&device);
+        self.set_members(name, &members)
+    }
+
+    pub fn list(&self) -> Result<Vec<(String, BTreeSet<u32>)>, GroupError> {
+        let mut list = Vec::new();
+        for entry in self.groups.iter() {
+            let (name, members) = entry?;
+            list.push((String::from_utf8_lossy(&name).into_owned(), bincode::deserialize(&members)?));
+        }
+        Ok(list)
+    }
+
+    pub fn tree(&self) -> &sled::Tree {
+        &self.groups
+    }
+
+    /// Writes removing devices from every group, applied with the removal of the devices
+    pub fn forget(&self, devices: &[u32]) -> Result<Vec<Write>, GroupError> {
+        let mut writes = Vec::new();
+        for (name, mut members) in self.list()? {
+            let before = members.len();
+            for device in devices {
+                members.remove(device);
+            }
+            if members.len() != before {
+                writes.push((name.into_bytes(), Some(bincode::serialize(&members)?)));
+            }
+        }
+        Ok(writes)
+    }
+}
@@ -1,7 +1,6 @@
 use structopt::StructOpt;
 
 mod objects;
-mod commands;
 
 pub mod home_manager {
     tonic::include_proto!("home_manager");
@@ -15,10 +14,60 @@ struct Config {
         default_value = "http://localhost:14563"
     )]
     address: http::Uri,
+    #[structopt(help = "PEM certificates of the authorities to trust, enables TLS", long, parse(from_os_str))]
+    ca: Option<std::path::PathBuf>,
+    #[structopt(help = "PEM certificate presented to the server, enables TLS", long, requires = "key", parse(from_os_str))]
+    cert: Option<std::path::PathBuf>,
+    #[structopt(help = "PEM private key of the certificate", long, requires = "cert", parse(from_os_str))]
+    key: Option<std::path::PathBuf>,
+    #[structopt(help = "the name expected in the server certificate, the host of the address by default", long)]
+    domain: Option<String>,
     #[structopt(subcommand)]
     action: Action,
 }
 
+/// Settings read from `home_manager/ctl.toml` in the user configuration directory
+#[derive(serde::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    token: Option<String>,
+}
+
+impl Settings {
+    fn load() -> Result<Settings, Box<dyn std::error::Error>> {
+        match dirs::config_dir().map(|d| d.join("home_manager").join("ctl.toml")) {
+            Some(path) if path.exists() => Ok(toml::from_str(&std::fs::read_to_string(path)?)?),
+            _ => Ok(Settings::default()),
+        }
+    }
+}
+
+/// The token sent with every request, if any
+struct Credentials {
+    authorization: Option<tonic::metadata::MetadataValue<tonic::metadata::Ascii>>,
+}
+
+impl Credentials {
+    /// The `HOME_MANAGER_TOKEN` environment variable takes precedence over the settings
+    fn new(settings: &Settings) -> Result<Credentials, Box<dyn std::error::Error>> {
+        let token = std::env::var("HOME_MANAGER_TOKEN").ok().or_else(|| settings.token.clone());
+        Ok(Credentials {
+            authorization: match token {
+                Some(token) => Some(format!("Bearer {}", token.trim()).parse()?),
+                None => None,
+            },
+        })
+    }
+
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        if let Some(authorization) = &self.authorization {
+            request.metadata_mut().insert("authorization", authorization.clone());
+        }
+        request
+    }
+}
+
 enum Status {
     On,
     Off,
@@ -48,10 +97,34 @@ enum Action {
         #[structopt(help = "the way to identify the object in the actionner", long, short)]
         id_in_actionner: String,
     },
+    #[structopt(about = "edit a device, keeping its id")]
+    UpdateDevice {
+        #[structopt(help = "the id of the device")]
+        id: u32,
+        #[structopt(help = "the new device name", long, short)]
+        name: Option<String>,
+        #[structopt(help = "the new category of the device", long, short)]
+        kind: Option<objects::ObjectKind>,
+        #[structopt(help = "the new actionner driving this object", long, short)]
+        actionner_id: Option<u32>,
+        #[structopt(help = "the new way to identify the object in the actionner", long, short)]
+        id_in_actionner: Option<String>,
+    },
+    #[structopt(about = "remove a device")]
+    RemoveDevice {
+        #[structopt(help = "the id of the device")]
+        id: u32,
+    },
     #[structopt(about = "list objects, optionaly limit to a category")]
     ListDevice {
         #[structopt(help = "the optional category to search")]
         category: Option<objects::ObjectKind>,
+        #[structopt(help = "only list devices driven by this actionner", long, short)]
+        actionner_id: Option<u32>,
+        #[structopt(help = "only list devices whose name contains this", long, short)]
+        name: Option<String>,
+        #[structopt(help = "only list devices of this group", long, short)]
+        group: Option<String>,
     },
     #[structopt(about = "adds a new actionner")]
     RegisterActionner {
@@ -62,15 +135,96 @@ enum Action {
         #[structopt(help = "the actionner name", long, short)]
         name: String,
     },
+    #[structopt(about = "remove an actionner")]
+    RemoveActionner {
+        #[structopt(help = "the id of the actionner")]
+        id: u32,
+        #[structopt(help = "also remove the devices driven by the actionner", long, short)]
+        cascade: bool,
+    },
     #[structopt(about = "lists all actionners")]
     ListActionners,
+    #[structopt(about = "lists the protocols supported by the server")]
+    ListProtocols,
+    #[structopt(about = "apply the home manifest of the server")]
+    Reload,
+    #[structopt(about = "export the whole home database as JSON")]
+    Export {
+        #[structopt(help = "the file to write, stdout if absent", parse(from_os_str))]
+        file: Option<std::path::PathBuf>,
+    },
+    #[structopt(about = "import a JSON export of the home database")]
+    Import {
+        #[structopt(help = "the file to read", parse(from_os_str))]
+        file: std::path::PathBuf,
+        #[structopt(help = "only report the problems", long, short)]
+        dry_run: bool,
+        #[structopt(help = "replace the entries whose id is already used", long, short)]
+        overwrite: bool,
+    },
     #[structopt(about = "issue an arduino command")]
     Arduino {
         #[structopt(help = "the id of the device")]
         id: u32,
         #[structopt(subcommand)]
         command: ArduinoCommand,
-    }
+    },
+    #[structopt(about = "list the groups of devices")]
+    ListGroups,
+    #[structopt(about = "manage or command a group of devices")]
+    Group {
+        #[structopt(help = "the name of the group")]
+        name: String,
+        #[structopt(subcommand)]
+        action: GroupAction,
+    },
+    #[structopt(about = "manage or activate scenes")]
+    Scene(SceneAction),
+    #[structopt(about = "manage the commands sent at fixed times")]
+    Schedule(ScheduleAction),
+    #[structopt(about = "manage the commands sent on events")]
+    Rule(RuleAction),
+    #[structopt(about = "print the events of the server as they happen")]
+    Watch {
+        #[structopt(help = "only print events of this device, may be repeated", long = "device", short = "d")]
+        devices: Vec<u32>,
+        #[structopt(help = "only print events of devices of this category", long, short)]
+        kind: Option<objects::ObjectKind>,
+        #[structopt(help = "only print events of this actionner and its devices", long, short)]
+        actionner_id: Option<u32>,
+    },
+    #[structopt(about = "print the readings of a sensor")]
+    Readings {
+        #[structopt(help = "the id of the sensor")]
+        id: u32,
+        #[structopt(help = "how many seconds back to look", long, short, default_value = "86400")]
+        since: i64,
+        #[structopt(help = "summarize the readings of each span of this many seconds", long, short)]
+        step: Option<u64>,
+    },
+    #[structopt(about = "record a reading of a sensor")]
+    RecordReading {
+        #[structopt(help = "the id of the sensor")]
+        id: u32,
+        #[structopt(help = "the value read")]
+        value: f64,
+    },
+    #[structopt(about = "manage the tokens allowed to call the server")]
+    Token(TokenAction),
+    #[structopt(about = "get the last known state of a device")]
+    State {
+        #[structopt(help = "the id of the device")]
+        id: u32,
+        #[structopt(help = "ask the actionner for the current state", long, short)]
+        refresh: bool,
+    },
+    #[structopt(about = "run the script of an ssh device")]
+    Ssh {
+        #[structopt(help = "the id of the device")]
+        id: u32,
+        #[structopt(help = "arguments given to the script")]
+        args: Vec<String>,
+    },
 }
 
 #[derive(StructOpt)]
@@ -78,32 +232,621 @@ pub enum ArduinoCommand {
     On,
     Off,
     Toggle,
+    Query,
+    #[structopt(about = "set the brightness of a dimmable or rgb light")]
+    Brightness {
+        #[structopt(help = "the brightness in percent, 0 turns the light off")]
+        level: u8,
+    },
+    #[structopt(about = "set the color of an rgb light")]
+    Color {
+        r: u8,
+        g: u8,
+        b: u8,
+    },
+}
+
+impl ArduinoCommand {
+    fn typed(self) -> home_manager::DeviceCommand {
+        use home_manager::device_command::{self, Command};
+        let command = match self {
+            ArduinoCommand::On => Command::Set(true),
+            ArduinoCommand::Off => Command::Set(false),
+            ArduinoCommand::Toggle => Command::Toggle(device_command::Toggle{}),
+            ArduinoCommand::Query => Command::Query(device_command::Query{}),
+            ArduinoCommand::Brightness{level} => Command::Brightness(device_command::Brightness{level: level.into()}),
+            ArduinoCommand::Color{r, g, b} => Command::Color(device_command::Color{r: r.into(), g: g.into(), b: b.into()}),
+        };
+        home_manager::DeviceCommand{command: Some(command)}
+    }
+}
+
+/// The commands changing the state of a device, the ones worth storing, as
+/// `on`, `off`, `toggle`, `brightness=<percent>` or `color=<r>,<g>,<b>`
+impl std::str::FromStr for ArduinoCommand {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid command {}, expected on, off, toggle, brightness=<percent> or color=<r>,<g>,<b>", s);
+        let command = s.trim().to_ascii_lowercase();
+        let mut parts = command.splitn(2, '=');
+        match (parts.next().unwrap_or(""), parts.next()) {
+            ("on", None) => Ok(ArduinoCommand::On),
+            ("off", None) => Ok(ArduinoCommand::Off),
+            ("toggle", None) => Ok(ArduinoCommand::Toggle),
+            ("brightness", Some(level)) => Ok(ArduinoCommand::Brightness{level: level.parse().map_err(|_| invalid())?}),
+            ("color", Some(color)) => {
+                let color: Vec<u8> = color.split(',').map(|c| c.trim().parse()).collect::<Result<_, _>>().map_err(|_| invalid())?;
+                match color[..] {
+                    [r, g, b] => Ok(ArduinoCommand::Color{r, g, b}),
+                    _ => Err(invalid()),
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A device and the arduino command it receives, written as `<id>:<command>`
+struct SceneStep {
+    object_id: u32,
+    command: home_manager::DeviceCommand,
+}
+
+impl std::str::FromStr for SceneStep {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let object_id = parts.next().unwrap_or("").trim().parse().map_err(|_| format!("Invalid device in {}", s))?;
+        let command: ArduinoCommand = parts.next().unwrap_or("").parse()?;
+        Ok(SceneStep{object_id, command: command.typed()})
+    }
+}
+
+#[derive(StructOpt)]
+enum SceneAction {
+    #[structopt(about = "create or replace a scene")]
+    Create {
+        #[structopt(help = "the name of the scene")]
+        name: String,
+        #[structopt(help = "the commands of the scene, as <device id>:<command>, the command being on, off, toggle, brightness=<percent> or color=<r>,<g>,<b>")]
+        steps: Vec<SceneStep>,
+    },
+    #[structopt(about = "delete a scene")]
+    Delete {
+        #[structopt(help = "the name of the scene")]
+        name: String,
+    },
+    #[structopt(about = "list the scenes")]
+    List,
+    #[structopt(about = "apply every command of a scene")]
+    Activate {
+        #[structopt(help = "the name of the scene")]
+        name: String,
+    },
+}
+
+#[derive(StructOpt)]
+enum ScheduleAction {
+    #[structopt(about = "send a command to a device at fixed times")]
+    Add {
+        #[structopt(help = "the id of the device")]
+        id: u32,
+        #[structopt(help = "the command to send: on, off, toggle, brightness=<percent> or color=<r>,<g>,<b>")]
+        command: ArduinoCommand,
+        #[structopt(help = "when to send it, as \"minute hour day-of-month month day-of-week\"", long, short)]
+        cron: Option<String>,
+        #[structopt(help = "send it every given number of seconds", long, short)]
+        every: Option<u64>,
+    },
+    #[structopt(about = "remove a schedule")]
+    Remove {
+        #[structopt(help = "the id of the schedule")]
+        id: u32,
+    },
+    #[structopt(about = "list the schedules")]
+    List,
+}
+
+/// An event, written as `state:<device id>[:<on|off>]`, `command:<device id>`,
+/// `online:<actionner id>` or `offline:<actionner id>`
+struct RuleEvent {
+    kind: home_manager::EventKind,
+    object_id: u32,
+    actionner_id: u32,
+    state: home_manager::Status,
+}
+
+impl std::str::FromStr for RuleEvent {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').map(|p| p.trim()).collect();
+        let id: u32 = parts.get(1).unwrap_or(&"").parse().map_err(|_| format!("Invalid id in {}", s))?;
+        let mut event = RuleEvent{
+            kind: home_manager::EventKind::StateChanged,
+            object_id: 0,
+            actionner_id: 0,
+            state: home_manager::Status::Unknown,
+        };
+        match (parts[0], parts.len()) {
+            ("state", 2) => event.object_id = id,
+            ("state", 3) => {
+                event.object_id = id;
+                event.state = match parts[2] {
+                    "on" => home_manager::Status::On,
+                    "off" => home_manager::Status::Off,
+                    _ => return Err(format!("Invalid state in {}, expected on or off", s)),
+                };
+            }
+            ("command", 2) => {
+                event.kind = home_manager::EventKind::CommandExecuted;
+                event.object_id = id;
+            }
+            ("online", 2) => {
+                event.kind = home_manager::EventKind::ActionnerOnline;
+                event.actionner_id = id;
+            }
+            ("offline", 2) => {
+                event.kind = home_manager::EventKind::ActionnerOffline;
+                event.actionner_id = id;
+            }
+            _ => return Err(format!("Invalid event {}", s)),
+        }
+        Ok(event)
+    }
+}
+
+fn event_name(kind: i32, object_id: u32, actionner_id: u32, state: i32) -> String {
+    match home_manager::EventKind::from_i32(kind) {
+        Some(home_manager::EventKind::StateChanged) => match home_manager::Status::from_i32(state) {
+            Some(home_manager::Status::Unknown) | None => format!("state:{}", object_id),
+            Some(_) => format!("state:{}:{}", object_id, state_name(state)),
+        },
+        Some(home_manager::EventKind::CommandExecuted) => format!("command:{}", object_id),
+        Some(home_manager::EventKind::ActionnerOnline) => format!("online:{}", actionner_id),
+        Some(home_manager::EventKind::ActionnerOffline) => format!("offline:{}", actionner_id),
+        _ => "unknown".to_owned(),
+    }
+}
+
+fn print_event(event: &home_manager::Event) {
+    let time = chrono::Local::now().format("%H:%M:%S");
+    match home_manager::EventKind::from_i32(event.kind) {
+        Some(home_manager::EventKind::StateChanged) => {
+            println!("{} device {} is now {}", time, event.object_id, state_name(event.state))
+        }
+        Some(home_manager::EventKind::CommandExecuted) => println!(
+            "{} device {} ran a command: {}, state: {}",
+            time,
+            event.object_id,
+            if event.success { "success" } else { "failure" },
+            state_name(event.state),
+        ),
+        Some(home_manager::EventKind::DeviceRegistered) => println!("{} device {} registered", time, event.object_id),
+        Some(home_manager::EventKind::DeviceRemoved) => println!("{} device {} removed", time, event.object_id),
+        Some(home_manager::EventKind::DeviceUpdated) => println!("{} device {} updated", time, event.object_id),
+        Some(home_manager::EventKind::ActionnerOnline) => println!("{} actionner {} is online", time, event.actionner_id),
+        Some(home_manager::EventKind::ActionnerOffline) => println!("{} actionner {} is offline", time, event.actionner_id),
+        None => println!("{} unknown event", time),
+    }
+}
+
+fn print_rules(rules: &[home_manager::Rule]) {
+    for rule in rules {
+        let steps: Vec<String> = rule.actions
+            .iter()
+            .map(|a| format!("{}:{}", a.object_id, command_name(a.typed.as_ref(), &a.command)))
+            .collect();
+        println!(
+            "{}: on {} {}",
+            rule.name,
+            event_name(rule.on, rule.object_id, rule.actionner_id, rule.state),
+            steps.join(" "),
+        );
+        if !rule.log.is_empty() {
+            println!("  log: {}", rule.log);
+        }
+    }
+}
+
+#[derive(StructOpt)]
+enum RuleAction {
+    #[structopt(about = "create or replace a rule")]
+    Create {
+        #[structopt(help = "the name of the rule")]
+        name: String,
+        #[structopt(help = "the event firing the rule: state:<device id>[:<on|off>], command:<device id>, online:<actionner id> or offline:<actionner id>")]
+        on: RuleEvent,
+        #[structopt(help = "the commands sent, as <device id>:<command>, the command being on, off, toggle, brightness=<percent> or color=<r>,<g>,<b>")]
+        steps: Vec<SceneStep>,
+        #[structopt(help = "a message logged by the server when the rule fires", long, short)]
+        log: Option<String>,
+    },
+    #[structopt(about = "delete a rule")]
+    Delete {
+        #[structopt(help = "the name of the rule")]
+        name: String,
+    },
+    #[structopt(about = "list the rules")]
+    List,
+    #[structopt(about = "list the rules an event would fire, without firing them")]
+    Evaluate {
+        #[structopt(help = "the event, as for create")]
+        event: RuleEvent,
+    },
+}
+
+#[derive(StructOpt)]
+enum TokenAction {
+    #[structopt(about = "create a token and print it, it can not be read again")]
+    Create {
+        #[structopt(help = "the name of the token")]
+        name: String,
+        #[structopt(help = "what the token allows: read-only, operator or admin", long, short, default_value = "read-only")]
+        role: TokenRole,
+    },
+    #[structopt(about = "revoke a token")]
+    Revoke {
+        #[structopt(help = "the name of the token")]
+        name: String,
+    },
+    #[structopt(about = "list the tokens")]
+    List,
+}
+
+struct TokenRole(home_manager::Role);
+
+impl std::str::FromStr for TokenRole {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "read-only" => Ok(TokenRole(home_manager::Role::ReadOnly)),
+            "operator" => Ok(TokenRole(home_manager::Role::Operator)),
+            "admin" => Ok(TokenRole(home_manager::Role::Admin)),
+            u => Err(format!("Unknown role: {}", u)),
+        }
+    }
+}
+
+fn role_name(role: i32) -> &'static str {
+    match home_manager::Role::from_i32(role) {
+        Some(home_manager::Role::ReadOnly) => "read-only",
+        Some(home_manager::Role::Operator) => "operator",
+        Some(home_manager::Role::Admin) => "admin",
+        None => "unknown",
+    }
+}
+
+#[derive(StructOpt)]
+enum GroupAction {
+    #[structopt(about = "create the group")]
+    Create,
+    #[structopt(about = "delete the group")]
+    Delete,
+    #[structopt(about = "add a device to the group")]
+    Add {
+        #[structopt(help = "the id of the device")]
+        id: u32,
+    },
+    #[structopt(about = "remove a device from the group")]
+    Remove {
+        #[structopt(help = "the id of the device")]
+        id: u32,
+    },
+    // Sends an arduino command to every device of the group
+    #[structopt(flatten)]
+    Command(ArduinoCommand),
 }
 
 use home_manager::{client::HomeManagerClient, ListDeviceRequest};
 
+fn state_name(state: i32) -> &'static str {
+    match home_manager::Status::from_i32(state) {
+        Some(home_manager::Status::On) => "on",
+        Some(home_manager::Status::Off) => "off",
+        _ => "unknown",
+    }
+}
+
+fn print_device_results(results: &[home_manager::DeviceCommandResult]) {
+    for result in results {
+        if !result.error.is_empty() {
+            println!("{}: error: {}", result.object_id, result.error);
+        } else {
+            println!(
+                "{}: {}, state: {}",
+                result.object_id,
+                if result.success { "success" } else { "failure" },
+                state_name(result.state),
+            );
+        }
+    }
+}
+
+/// The listed command, the encoded bytes are only counted when the server could not decode them
+fn command_name(typed: Option<&home_manager::DeviceCommand>, command: &[u8]) -> String {
+    use home_manager::device_command::Command;
+    match typed.and_then(|t| t.command.as_ref()) {
+        Some(Command::Set(true)) => "on".to_owned(),
+        Some(Command::Set(false)) => "off".to_owned(),
+        Some(Command::Toggle(_)) => "toggle".to_owned(),
+        Some(Command::Query(_)) => "query".to_owned(),
+        Some(Command::Read(_)) => "read".to_owned(),
+        Some(Command::Brightness(brightness)) => format!("brightness={}", brightness.level),
+        Some(Command::Color(color)) => format!("color={},{},{}", color.r, color.g, color.b),
+        Some(Command::Run(run)) => format!("run {}", run.args.join(" ")).trim_end().to_owned(),
+        None => format!("<{} bytes>", command.len()),
+    }
+}
+
+fn time_name(timestamp: i64) -> String {
+    use chrono::TimeZone;
+    chrono::Local.timestamp(timestamp, 0).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn print_command_reply(reply: &home_manager::CommandReply) {
+    println!("{}", if reply.success { "success" } else { "failure" });
+    println!("state: {}", state_name(reply.state));
+    if !reply.reply.is_empty() {
+        println!("{}", reply.reply.trim_end());
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Config::from_args();
-    let mut client = HomeManagerClient::connect(args.address)?;
+    let settings = Settings::load()?;
+    let credentials = Credentials::new(&settings)?;
+    let mut client = if args.ca.is_some() || args.cert.is_some() {
+        let mut tls = tonic::transport::ClientTlsConfig::with_rustls();
+        if let Some(ca) = &args.ca {
+            tls.ca_certificate(tonic::transport::Certificate::from_pem(std::fs::read(ca)?));
+        }
+        if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+            tls.identity(tonic::transport::Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+        }
+        if let Some(domain) = &args.domain {
+            tls.domain_name(domain.clone());
+        }
+        let mut endpoint = tonic::transport::Channel::builder(args.address);
+        endpoint.tls_config(&tls);
+        HomeManagerClient::new(endpoint.channel())
+    } else {
+        HomeManagerClient::connect(args.address)?
+    };
     match args.action {
         Action::Arduino{id: object_id, command} => {
-            let command = bincode::serialize(&match command {
-                ArduinoCommand::On => commands::ArduinoCommand::Set{state: true},
-                ArduinoCommand::Off => commands::ArduinoCommand::Set{state: false},
-                ArduinoCommand::Toggle => commands::ArduinoCommand::Toggle,
-            })?;
-            let request = tonic::Request::new(
+            let request = credentials.request(
                 home_manager::CommandRequest {
-                    command,
+                    command: Vec::new(),
                     object_id,
+                    typed: Some(command.typed()),
                 }
             );
-            let respsonse = client.command(request).await?;
-            println!("RESPONSE={:?}", respsonse);
+            let response = client.command(request).await?.into_inner();
+            print_command_reply(&response);
+        }
+        Action::Ssh{id: object_id, args} => {
+            let run = home_manager::device_command::Run{args};
+            let request = credentials.request(
+                home_manager::CommandRequest {
+                    command: Vec::new(),
+                    object_id,
+                    typed: Some(home_manager::DeviceCommand{command: Some(home_manager::device_command::Command::Run(run))}),
+                }
+            );
+            let response = client.command(request).await?.into_inner();
+            print_command_reply(&response);
+        }
+        Action::ListGroups => {
+            let request = credentials.request(home_manager::ListGroupsRequest{});
+            let response = client.list_groups(request).await?.into_inner();
+            for group in response.groups {
+                let members: Vec<String> = group.object_ids.iter().map(|id| id.to_string()).collect();
+                println!("{}: {}", group.name, members.join(", "));
+            }
+        }
+        Action::Group { name, action } => match action {
+            GroupAction::Create => {
+                client.create_group(credentials.request(home_manager::CreateGroupRequest{name})).await?;
+            }
+            GroupAction::Delete => {
+                client.delete_group(credentials.request(home_manager::DeleteGroupRequest{name})).await?;
+            }
+            GroupAction::Add { id } => {
+                let request = home_manager::AddToGroupRequest{group: name, object_id: id};
+                client.add_to_group(credentials.request(request)).await?;
+            }
+            GroupAction::Remove { id } => {
+                let request = home_manager::RemoveFromGroupRequest{group: name, object_id: id};
+                client.remove_from_group(credentials.request(request)).await?;
+            }
+            GroupAction::Command(command) => {
+                let request = credentials.request(home_manager::GroupCommandRequest{
+                    group: name,
+                    command: Vec::new(),
+                    typed: Some(command.typed()),
+                });
+                let response = client.group_command(request).await?.into_inner();
+                print_device_results(&response.results);
+            }
+        },
+        Action::Scene(action) => match action {
+            SceneAction::Create { name, steps } => {
+                let mut actions = Vec::with_capacity(steps.len());
+                for step in steps {
+                    actions.push(home_manager::SceneAction{
+                        object_id: step.object_id,
+                        command: Vec::new(),
+                        typed: Some(step.command),
+                    });
+                }
+                let request = credentials.request(home_manager::CreateSceneRequest{name, actions});
+                client.create_scene(request).await?;
+            }
+            SceneAction::Delete { name } => {
+                client.delete_scene(credentials.request(home_manager::DeleteSceneRequest{name})).await?;
+            }
+            SceneAction::List => {
+                let request = credentials.request(home_manager::ListScenesRequest{});
+                let response = client.list_scenes(request).await?.into_inner();
+                for scene in response.scenes {
+                    let steps: Vec<String> = scene.actions
+                        .iter()
+                        .map(|a| format!("{}:{}", a.object_id, command_name(a.typed.as_ref(), &a.command)))
+                        .collect();
+                    println!("{}: {}", scene.name, steps.join(" "));
+                }
+            }
+            SceneAction::Activate { name } => {
+                let request = credentials.request(home_manager::ActivateSceneRequest{name});
+                let response = client.activate_scene(request).await?.into_inner();
+                print_device_results(&response.results);
+            }
+        },
+        Action::Schedule(action) => match action {
+            ScheduleAction::Add { id: object_id, command, cron, every } => {
+                let request = credentials.request(home_manager::CreateScheduleRequest{
+                    object_id,
+                    command: Vec::new(),
+                    cron: cron.unwrap_or_default(),
+                    interval_seconds: every.unwrap_or_default(),
+                    typed: Some(command.typed()),
+                });
+                let response = client.create_schedule(request).await?.into_inner();
+                println!("Schedule {}, next run at {}", response.id, time_name(response.next_run));
+            }
+            ScheduleAction::Remove { id } => {
+                client.delete_schedule(credentials.request(home_manager::DeleteScheduleRequest{id})).await?;
+            }
+            ScheduleAction::List => {
+                let request = credentials.request(home_manager::ListSchedulesRequest{});
+                let response = client.list_schedules(request).await?.into_inner();
+                for schedule in response.schedules {
+                    let trigger = if schedule.cron.is_empty() {
+                        format!("every {}s", schedule.interval_seconds)
+                    } else {
+                        format!("cron \"{}\"", schedule.cron)
+                    };
+                    let next_run = if schedule.next_run == 0 {
+                        "disabled".to_owned()
+                    } else {
+                        format!("next run at {}", time_name(schedule.next_run))
+                    };
+                    println!(
+                        "{}: {} on {}, {}, {}",
+                        schedule.id,
+                        command_name(schedule.typed.as_ref(), &schedule.command),
+                        schedule.object_id,
+                        trigger,
+                        next_run,
+                    );
+                }
+            }
+        },
+        Action::Rule(action) => match action {
+            RuleAction::Create { name, on, steps, log } => {
+                let mut actions = Vec::with_capacity(steps.len());
+                for step in steps {
+                    actions.push(home_manager::SceneAction{
+                        object_id: step.object_id,
+                        command: Vec::new(),
+                        typed: Some(step.command),
+                    });
+                }
+                let rule = home_manager::Rule{
+                    name,
+                    on: on.kind as i32,
+                    object_id: on.object_id,
+                    actionner_id: on.actionner_id,
+                    state: on.state as i32,
+                    actions,
+                    log: log.unwrap_or_default(),
+                };
+                client.create_rule(credentials.request(home_manager::CreateRuleRequest{rule: Some(rule)})).await?;
+            }
+            RuleAction::Delete { name } => {
+                client.delete_rule(credentials.request(home_manager::DeleteRuleRequest{name})).await?;
+            }
+            RuleAction::List => {
+                let response = client.list_rules(credentials.request(home_manager::ListRulesRequest{})).await?.into_inner();
+                print_rules(&response.rules);
+            }
+            RuleAction::Evaluate { event } => {
+                let event = home_manager::Event{
+                    kind: event.kind as i32,
+                    object_id: event.object_id,
+                    actionner_id: event.actionner_id,
+                    state: event.state as i32,
+                    success: true,
+                    kind_id: 0,
+                };
+                let request = credentials.request(home_manager::EvaluateRulesRequest{event: Some(event)});
+                let response = client.evaluate_rules(request).await?.into_inner();
+                print_rules(&response.rules);
+            }
+        },
+        Action::Watch{devices, kind, actionner_id} => {
+            let request = credentials.request(home_manager::WatchEventsRequest{
+                object_ids: devices,
+                kind_id: kind.map(|kind| kind.id()).unwrap_or(0),
+                by_actionner: actionner_id.is_some(),
+                actionner_id: actionner_id.unwrap_or(0),
+            });
+            let mut stream = client.watch_events(request).await?.into_inner();
+            while let Some(event) = stream.message().await? {
+                print_event(&event);
+            }
+        }
+        Action::Readings{id: object_id, since, step} => {
+            let now = chrono::Local::now().timestamp();
+            let request = credentials.request(home_manager::GetReadingsRequest{
+                object_id,
+                from: now - since,
+                to: now,
+                step: step.unwrap_or(0),
+            });
+            let response = client.get_readings(request).await?.into_inner();
+            for reading in response.readings {
+                if step.is_some() {
+                    println!(
+                        "{}: {:.2} (min {:.2}, max {:.2}, {} readings)",
+                        time_name(reading.time),
+                        reading.value,
+                        reading.min,
+                        reading.max,
+                        reading.count,
+                    );
+                } else {
+                    println!("{}: {}", time_name(reading.time), reading.value);
+                }
+            }
+        }
+        Action::RecordReading{id: object_id, value} => {
+            let request = credentials.request(home_manager::RecordReadingRequest{object_id, value, time: 0});
+            client.record_reading(request).await?;
+        }
+        Action::Token(action) => match action {
+            TokenAction::Create { name, role } => {
+                let request = credentials.request(home_manager::CreateTokenRequest{name, role: role.0 as i32});
+                println!("{}", client.create_token(request).await?.into_inner().token);
+            }
+            TokenAction::Revoke { name } => {
+                client.revoke_token(credentials.request(home_manager::RevokeTokenRequest{name})).await?;
+            }
+            TokenAction::List => {
+                let response = client.list_tokens(credentials.request(home_manager::ListTokensRequest{})).await?.into_inner();
+                for token in response.tokens {
+                    println!("{}: {}, created {}", token.name, role_name(token.role), time_name(token.created as i64));
+                }
+            }
+        },
+        Action::State{id: object_id, refresh} => {
+            let request = credentials.request(home_manager::GetDeviceStateRequest{object_id, refresh});
+            let response = client.get_device_state(request).await?.into_inner();
+            println!("{}", state_name(response.state));
         }
         Action::RegisterDevice{name, actionner_id, id_in_actionner, kind} => {
-            let request = tonic::Request::new(
+            let request = credentials.request(
                 home_manager::RegisterDeviceRequest {
                     kind: kind.name(),
                     actionner_id,
@@ -113,9 +856,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let respsonse = client.register_device(request).await?.into_inner();
             println!("RESPONSE={:?}", respsonse);
         }
-        Action::ListDevice { category } => {
-            let request = tonic::Request::new(ListDeviceRequest {
+        Action::ListDevice { category, actionner_id, name, group } => {
+            let request = credentials.request(ListDeviceRequest {
                 kind_id: category.map(|kind| kind.id()).unwrap_or(0),
+                by_actionner: actionner_id.is_some(),
+                actionner_id: actionner_id.unwrap_or(0),
+                name_contains: name.unwrap_or_default(),
+                group: group.unwrap_or_default(),
             });
             let response = client.list_device(request).await?.into_inner();
             println!("RESPONSE={:?}", response);
@@ -125,7 +872,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             protocol,
             name,
         } => {
-            let request = tonic::Request::new(home_manager::RegisterActionnerRequest {
+            let request = credentials.request(home_manager::RegisterActionnerRequest {
                 remote,
                 protocol: protocol.name(),
                 name,
@@ -133,10 +880,87 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let respsonse = client.register_actionner(request).await?.into_inner();
             println!("RESPONSE={:?}", respsonse);
         }
+        Action::UpdateDevice { id, name, kind, actionner_id, id_in_actionner } => {
+            let request = credentials.request(home_manager::UpdateDeviceRequest {
+                id,
+                name: name.unwrap_or_default(),
+                kind: kind.map(|k| k.name()).unwrap_or_default(),
+                change_actionner: actionner_id.is_some(),
+                actionner_id: actionner_id.unwrap_or(0),
+                id_in_actionner: id_in_actionner.unwrap_or_default(),
+            });
+            client.update_device(request).await?;
+        }
+        Action::RemoveDevice { id } => {
+            let request = credentials.request(home_manager::RemoveDeviceRequest{id});
+            client.remove_device(request).await?;
+        }
+        Action::RemoveActionner { id, cascade } => {
+            let request = credentials.request(home_manager::RemoveActionnerRequest{id, cascade});
+            let response = client.remove_actionner(request).await?.into_inner();
+            for device in response.removed_devices {
+                println!("removed device {}", device);
+            }
+        }
         Action::ListActionners => {
-            let request = tonic::Request::new(home_manager::ListActionnerRequest{});
+            let request = credentials.request(home_manager::ListActionnerRequest{});
             let respsonse = client.list_actionner(request).await?.into_inner();
-            println!("RESPONSE:{:?}", respsonse)
+            for actionner in respsonse.actionners {
+                println!(
+                    "{}: {} ({}) {}, last seen {}, latency {}ms",
+                    actionner.id,
+                    actionner.name,
+                    actionner.protocol,
+                    if actionner.online { "online" } else { "offline" },
+                    actionner.last_seen,
+                    actionner.latency_ms,
+                );
+            }
+        }
+        Action::Reload => {
+            let request = credentials.request(home_manager::ReloadRequest{});
+            let response = client.reload(request).await?.into_inner();
+            for change in response.created {
+                println!("created {}", change);
+            }
+            for change in response.updated {
+                println!("updated {}", change);
+            }
+            for change in response.removed {
+                println!("removed {}", change);
+            }
+        }
+        Action::Export { file } => {
+            let request = credentials.request(home_manager::ExportStateRequest{});
+            let response = client.export_state(request).await?.into_inner();
+            match file {
+                Some(file) => std::fs::write(file, response.document)?,
+                None => println!("{}", response.document),
+            }
+        }
+        Action::Import { file, dry_run, overwrite } => {
+            let request = credentials.request(home_manager::ImportStateRequest{
+                document: std::fs::read_to_string(file)?,
+                dry_run,
+                overwrite,
+            });
+            let response = client.import_state(request).await?.into_inner();
+            for problem in &response.problems {
+                println!("{}", problem);
+            }
+            if response.applied {
+                println!("imported");
+            } else {
+                println!("nothing imported");
+            }
+        }
+        Action::ListProtocols => {
+            let request = credentials.request(home_manager::ListProtocolRequest{});
+            let response = client.list_protocol(request).await?.into_inner();
+            for protocol in response.protocols {
+                println!("{}: {}", protocol.name, protocol.desc);
+                println!("\tcommands: {}", protocol.commands.join(", "));
+            }
         }
     }
     Ok(())
@@ -0,0 +1,219 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::Mutex;
+
+use crate::events::EventBus;
+use crate::objects::{Object, ObjectKind, Protocol};
+use crate::{ActionnerData, ActionnerError, Actionners, DeviceBatch, DeviceError, Devices};
+
+/// Declarative description of the home, applied on top of the database
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Remove the actionners and devices that are not in the manifest
+    #[serde(default)]
+    pub prune: bool,
+    #[serde(default, rename = "actionner")]
+    pub actionners: Vec<ManifestActionner>,
+    #[serde(default, rename = "device")]
+    pub devices: Vec<ManifestDevice>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestActionner {
+    pub name: String,
+    pub protocol: String,
+    pub remote: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestDevice {
+    pub name: String,
+    pub kind: String,
+    /// Name of the actionner driving the device
+    pub actionner: String,
+    pub id_in_actionner: String,
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Invalid(String),
+    Actionners(ActionnerError),
+    Devices(DeviceError),
+}
+impl From<std::io::Error> for ManifestError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+impl From<toml::de::Error> for ManifestError {
+    fn from(err: toml::de::Error) -> Self {
+        Self::Toml(err)
+    }
+}
+impl From<ActionnerError> for ManifestError {
+    fn from(err: ActionnerError) -> Self {
+        Self::Actionners(err)
+    }
+}
+impl From<DeviceError> for ManifestError {
+    fn from(err: DeviceError) -> Self {
+        Self::Devices(err)
+    }
+}
+impl std::fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ManifestError::Io(e) => write!(f, "could not read manifest: {}", e),
+            ManifestError::Toml(e) => write!(f, "invalid manifest: {}", e),
+            ManifestError::Invalid(e) => write!(f, "invalid manifest: {}", e),
+            ManifestError::Actionners(e) => write!(f, "could not apply manifest: {:?}", e),
+            ManifestError::Devices(e) => write!(f, "could not apply manifest: {:?}", e),
+        }
+    }
+}
+impl std::error::Error for ManifestError {}
+
+/// What applying a manifest changed, entries are described as `actionner <name>` or `device <name>`
+#[derive(Default)]
+pub struct Report {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl Manifest {
+    pub fn load(path: &std::path::Path) -> Result<Manifest, ManifestError> {
+        let manifest: Manifest = toml::from_str(&std::fs::read_to_string(path)?)?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    fn validate(&self) -> Result<(), ManifestError> {
+        let mut names = HashSet::new();
+        for actionner in &self.actionners {
+            if !names.insert(&actionner.name) {
+                return Err(ManifestError::Invalid(format!("duplicate actionner {}", actionner.name)));
+            }
+            if actionner.protocol.parse::<Protocol>().is_err() {
+                return Err(ManifestError::Invalid(format!("unknown protocol {}", actionner.protocol)));
+            }
+        }
+        let mut devices = HashSet::new();
+        for device in &self.devices {
+            if !devices.insert(&device.name) {
+                return Err(ManifestError::Invalid(format!("duplicate device {}", device.name)));
+            }
+            let kind: ObjectKind = match device.kind.parse() {
+                Ok(kind) => kind,
+                Err(_) => return Err(ManifestError::Invalid(format!("unknown kind {}", device.kind))),
+            };
+            let actionner = match self.actionners.iter().find(|a| a.name == device.actionner) {
+                Some(a) => a,
+                None => return Err(ManifestError::Invalid(format!("unknown actionner {}", device.actionner))),
+            };
+            let protocol: Protocol = actionner.protocol.parse().unwrap();
+            if !protocol.drives(&kind) {
+                return Err(ManifestError::Invalid(format!("{} can't drive device {}", actionner.name, device.name)));
+            }
+            if protocol.parse_id(&device.id_in_actionner).is_err() {
+                return Err(ManifestError::Invalid(format!("invalid id for device {}", device.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reconciles the database with the manifest, matching entries by name.
+    ///
+    /// New actionners are connected before taking the locks, those that can't
+    /// be reached are created offline. The actionners and then the devices are
+    /// each written in a single transaction.
+    pub async fn apply(&self, devices: &Mutex<Devices>, actionners: &Mutex<Actionners>, events: &EventBus) -> Result<Report, ManifestError> {
+        let wanted: Vec<ActionnerData> = self.actionners.iter().map(|actionner| ActionnerData {
+            protocol: actionner.protocol.parse().unwrap(),
+            remote: actionner.remote.clone(),
+            name: actionner.name.clone(),
+        }).collect();
+        let mut connected = Actionners::connect_new(actionners, &wanted).await;
+        let mut devices = devices.lock().await;
+        let mut actionners = actionners.lock().await;
+
+        let mut report = Report::default();
+        let current = actionners.get_data();
+        let mut changes = Vec::new();
+        let mut actionner_ids = HashMap::new();
+        for data in wanted {
+            let id = match current.iter().find(|(_, old)| old.name == data.name) {
+                Some((id, old)) => {
+                    if old.protocol != data.protocol || old.remote != data.remote {
+                        report.updated.push(format!("actionner {}", data.name));
+                        changes.push((*id, Some(data.clone())));
+                    }
+                    *id
+                }
+                None => {
+                    let id = actionners.reserve_id();
+                    report.created.push(format!("actionner {}", data.name));
+                    changes.push((id, Some(data.clone())));
+                    id
+                }
+            };
+            actionner_ids.insert(data.name, (id, data.protocol));
+        }
+        if self.prune {
+            for (id, data) in &current {
+                if !actionner_ids.contains_key(&data.name) {
+                    changes.push((*id, None));
+                    report.removed.push(format!("actionner {}", data.name));
+                }
+            }
+        }
+
+        let existing = devices.list()?;
+        let mut batch = DeviceBatch::default();
+        let mut kept = HashSet::new();
+        for device in &self.devices {
+            let (actionner_id, protocol) = actionner_ids[&device.actionner];
+            let kind: ObjectKind = device.kind.parse().unwrap();
+            let id_in_actionner = protocol.parse_id(&device.id_in_actionner).unwrap();
+            let new_obj = Object {
+                name: device.name.clone(),
+                kind,
+                actionner_id,
+                id_in_actionner,
+            };
+            match existing.iter().find(|(_, obj)| obj.name == device.name) {
+                Some((id, obj)) => {
+                    kept.insert(*id);
+                    let rehomed = obj.actionner_id != actionner_id || obj.id_in_actionner.repr() != new_obj.id_in_actionner.repr();
+                    if rehomed || obj.kind.id() != new_obj.kind.id() {
+                        batch.put.push((*id, new_obj, rehomed));
+                        report.updated.push(format!("device {}", device.name));
+                    }
+                }
+                None => {
+                    batch.add.push(new_obj);
+                    report.created.push(format!("device {}", device.name));
+                }
+            }
+        }
+        // The devices of the pruned actionners are not in the manifest either
+        if self.prune {
+            batch.remove = existing.iter().map(|(id, _)| *id).filter(|id| !kept.contains(id)).collect();
+        }
+
+        // Devices left without actionner by a crash in between are removed when
+        // the devices are opened again
+        actionners.write_all(changes, &mut connected)?;
+        let applied = devices.apply(batch)?;
+        applied.publish(&devices, events)?;
+        for (_, obj) in &applied.removed {
+            report.removed.push(format!("device {}", obj.name));
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,224 @@
+//! Versioning of the sled databases.
+//!
+//! Each database records the version of its layout in a `meta` tree. When a
+//! database is opened the migrations between its version and the current one
+//! are run in order, each one rewriting the records it changes.
+//!
+//! A migration must never be edited once released: a new layout means a new
+//! migration appended to the list, along with a copy of the structures it
+//! reads so that they stay decodable.
+
+const VERSION_KEY: &[u8] = b"schema_version";
+
+/// Upgrades a database from one version to the next
+pub type Migration = fn(&sled::Db) -> Result<(), MigrationError>;
+
+pub struct Schema {
+    pub name: &'static str,
+    /// `migrations[n]` upgrades a database from version `n` to `n + 1`
+    pub migrations: &'static [Migration],
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sled(sled::Error),
+    Serde(bincode::Error),
+    /// The database was written by a more recent server
+    TooNew { found: u32, supported: u32 },
+}
+impl From<sled::Error> for MigrationError {
+    fn from(err: sled::Error) -> Self {
+        Self::Sled(err)
+    }
+}
+impl From<bincode::Error> for MigrationError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Serde(err)
+    }
+}
+
+impl Schema {
+    pub fn version(&self) -> u32 {
+        self.migrations.len() as u32
+    }
+
+    /// Brings the database to the current version.
+    ///
+    /// A database without version is either new, and gets the current one, or
+    /// was written before versioning and is at version 0.
+    pub fn upgrade(&self, db: &sled::Db) -> Result<(), MigrationError> {
+        let meta = db.open_tree("meta")?;
+        let mut version = match meta.get(VERSION_KEY)? {
+            Some(v) => bincode::deserialize(&v)?,
+            None if is_empty(db)? => self.version(),
+            None => 0,
+        };
+        if version > self.version() {
+            return Err(MigrationError::TooNew {
+                found: version,
+                supported: self.version(),
+            });
+        }
+        while version < self.version() {
+            tracing::info!("Migrating {} from version {} to {}", self.name, version, version + 1);
+            self.migrations[version as usize](db)?;
+            version += 1;
+            meta.insert(VERSION_KEY, bincode::serialize(&version)?)?;
+            db.flush()?;
+        }
+        meta.insert(VERSION_KEY, bincode::serialize(&version)?)?;
+        Ok(())
+    }
+}
+
+/// Whether neither the default tree nor any named tree holds a record
+fn is_empty(db: &sled::Db) -> Result<bool, MigrationError> {
+    if !db.is_empty() {
+        return Ok(false);
+    }
+    for name in db.tree_names() {
+        if !db.open_tree(name)?.is_empty() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+pub const DEVICES: Schema = Schema {
+    name: "devices",
+    migrations: &[devices_v1],
+};
+
+pub const ACTIONNERS: Schema = Schema {
+    name: "actionners",
+    migrations: &[actionners_v1],
+};
+
+pub const AUTH: Schema = Schema {
+    name: "auth",
+    migrations: &[],
+};
+
+/// Version 1 only starts recording the version, records keep the unversioned layout
+fn devices_v1(_db: &sled::Db) -> Result<(), MigrationError> {
+    Ok(())
+}
+
+/// Version 1 only starts recording the version, records keep the unversioned layout
+fn actionners_v1(_db: &sled::Db) -> Result<(), MigrationError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marked(db: &sled::Db) -> Result<(), MigrationError> {
+        db.open_tree("marks")?.insert(b"v1", &b""[..])?;
+        Ok(())
+    }
+
+    const MARKED: Schema = Schema {
+        name: "marked",
+        migrations: &[marked],
+    };
+
+    fn was_migrated(db: &sled::Db) -> bool {
+        db.open_tree("marks").unwrap().get(b"v1").unwrap().is_some()
+    }
+
+    fn version(db: &sled::Db) -> u32 {
+        bincode::deserialize(&db.open_tree("meta").unwrap().get(VERSION_KEY).unwrap().unwrap()).unwrap()
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// Writes a fixture to a new database in `dir`
+    fn fixture(dir: &std::path::Path, name: &str, records: &str) {
+        let db = sled::Db::open(dir.join(name)).unwrap();
+        for line in records.lines().filter(|line| !line.starts_with('#')) {
+            let mut fields = line.split(' ');
+            db.insert(hex(fields.next().unwrap()), hex(fields.next().unwrap())).unwrap();
+        }
+        db.flush().unwrap();
+    }
+
+    #[test]
+    fn new_database_is_not_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::Db::open(dir.path()).unwrap();
+        MARKED.upgrade(&db).unwrap();
+        assert!(!was_migrated(&db));
+        assert_eq!(version(&db), 1);
+    }
+
+    #[test]
+    fn records_in_named_trees_are_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::Db::open(dir.path()).unwrap();
+        db.open_tree("groups").unwrap().insert(b"kitchen", &b""[..]).unwrap();
+        MARKED.upgrade(&db).unwrap();
+        assert!(was_migrated(&db));
+        assert_eq!(version(&db), 1);
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::Db::open(dir.path()).unwrap();
+        db.open_tree("meta").unwrap().insert(VERSION_KEY, bincode::serialize(&2u32).unwrap()).unwrap();
+        match MARKED.upgrade(&db) {
+            Err(MigrationError::TooNew { found: 2, supported: 1 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn baseline_devices_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path(), "devices", include_str!("fixtures/baseline_devices.txt"));
+        let known = [0, 1].iter().copied().collect();
+        let devices = crate::Devices::open(dir.path().to_owned(), &known).unwrap();
+        let mut list: Vec<_> = devices
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|(id, obj)| (id, obj.name, obj.actionner_id, obj.kind.name(), obj.id_in_actionner.repr()))
+            .collect();
+        list.sort_by_key(|(id, ..)| *id);
+        let expected = [(0, "lamp", 0, "LED", "3"), (1, "desk light", 1, "LED", "desk"), (4, "porch", 0, "LED", "-2")];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(id, name, actionner_id, kind, id_in_actionner)| (id, name.to_owned(), actionner_id, kind.to_owned(), id_in_actionner.to_owned()))
+            .collect();
+        assert_eq!(list, expected);
+        drop(devices);
+        let db = sled::Db::open(dir.path().join("devices")).unwrap();
+        assert_eq!(version(&db), DEVICES.version());
+    }
+
+    #[tokio::test]
+    async fn baseline_actionners_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(dir.path(), "actionners", include_str!("fixtures/baseline_actionners.txt"));
+        // Both actionners are unreachable and kept offline
+        let mut settings = crate::config::ProtocolSettings::default();
+        settings.ssh.program = "false".to_owned();
+        let actionners = crate::Actionners::open(dir.path().to_owned(), settings).await.unwrap();
+        let mut list = actionners.get_data();
+        list.sort_by_key(|(id, _)| *id);
+        let list: Vec<_> = list.into_iter().map(|(id, data)| (id, data.name, data.protocol.name(), data.remote)).collect();
+        assert_eq!(
+            list,
+            vec![
+                (0, "living-room".to_owned(), "Arduino".to_owned(), "127.0.0.1:1".to_owned()),
+                (1, "office".to_owned(), "SSH".to_owned(), "pi@[::1]:2222".to_owned()),
+            ]
+        );
+        drop(actionners);
+        let db = sled::Db::open(dir.path().join("actionners")).unwrap();
+        assert_eq!(version(&db), ACTIONNERS.version());
+    }
+}
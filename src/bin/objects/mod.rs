@@ -22,13 +22,34 @@ pub struct Object {
     pub name: String,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Last known state of a device
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceState {
+    Unknown,
+    On,
+    Off,
+}
+
+impl DeviceState {
+    pub fn toggled(self) -> DeviceState {
+        match self {
+            DeviceState::Unknown => DeviceState::Unknown,
+            DeviceState::On => DeviceState::Off,
+            DeviceState::Off => DeviceState::On,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum Protocol {
     Arduino,
     SSH,
 }
 
 impl Protocol {
+    pub fn all() -> &'static [Protocol] {
+        &[Protocol::Arduino, Protocol::SSH]
+    }
     pub fn name(&self) -> String {
         match self {
             Protocol::Arduino => "Arduino",
@@ -36,6 +57,36 @@ impl Protocol {
         }
         .to_owned()
     }
+    pub fn desc(&self) -> String {
+        match self {
+            Protocol::Arduino => "Arduino board driving pins over TCP, remote is host:port",
+            Protocol::SSH => "Scripts run on a host over ssh, remote is [user@]host[:port]",
+        }
+        .to_owned()
+    }
+    /// Parses the way a device is identified in an actionner of this protocol
+    pub fn parse_id(&self, id: &str) -> Result<ActionnerId, &'static str> {
+        match self {
+            Protocol::Arduino => id.trim().parse().map(ActionnerId::Arduino).map_err(|_| "invalid arduino pin"),
+            Protocol::SSH if id.trim().is_empty() => Err("empty ssh command"),
+            Protocol::SSH => Ok(ActionnerId::SSH(id.to_owned())),
+        }
+    }
+    /// Whether actionners of this protocol have commands for this kind of device
+    pub fn drives(&self, kind: &ObjectKind) -> bool {
+        match (self, kind) {
+            (Protocol::Arduino, _) => true,
+            (Protocol::SSH, ObjectKind::LED) => true,
+            (Protocol::SSH, kind) => kind.is_sensor(),
+        }
+    }
+    pub fn commands(&self) -> Vec<String> {
+        let commands: &[&str] = match self {
+            Protocol::Arduino => &["on", "off", "toggle", "query", "brightness", "color", "read"],
+            Protocol::SSH => &["run"],
+        };
+        commands.iter().map(|c| (*c).to_owned()).collect()
+    }
 }
 
 impl std::str::FromStr for Protocol {
@@ -52,17 +103,48 @@ impl std::str::FromStr for Protocol {
 #[derive(Serialize, Deserialize)]
 pub enum ObjectKind {
     LED,
+    /// A light whose brightness can be set
+    DimmableLight,
+    /// A light whose color, and so brightness, can be set
+    RgbLight,
+    /// Degrees Celsius
+    TemperatureSensor,
+    /// Relative humidity in percent
+    HumiditySensor,
+    /// 1 when motion is detected, 0 otherwise
+    MotionSensor,
+    /// 1 when the contact is closed, 0 when open
+    ContactSensor,
 }
 
 impl ObjectKind {
     pub fn name(&self) -> String {
         match self {
             ObjectKind::LED => "LED".to_owned(),
+            ObjectKind::DimmableLight => "DimmableLight".to_owned(),
+            ObjectKind::RgbLight => "RgbLight".to_owned(),
+            ObjectKind::TemperatureSensor => "TemperatureSensor".to_owned(),
+            ObjectKind::HumiditySensor => "HumiditySensor".to_owned(),
+            ObjectKind::MotionSensor => "MotionSensor".to_owned(),
+            ObjectKind::ContactSensor => "ContactSensor".to_owned(),
         }
     }
     pub fn id(&self) -> u32 {
         match self {
             ObjectKind::LED => 1,
+            ObjectKind::DimmableLight => 2,
+            ObjectKind::RgbLight => 3,
+            ObjectKind::TemperatureSensor => 4,
+            ObjectKind::HumiditySensor => 5,
+            ObjectKind::MotionSensor => 6,
+            ObjectKind::ContactSensor => 7,
+        }
+    }
+    /// Sensors are read rather than commanded
+    pub fn is_sensor(&self) -> bool {
+        match self {
+            ObjectKind::LED | ObjectKind::DimmableLight | ObjectKind::RgbLight => false,
+            ObjectKind::TemperatureSensor | ObjectKind::HumiditySensor | ObjectKind::MotionSensor | ObjectKind::ContactSensor => true,
         }
     }
 }
@@ -72,6 +154,12 @@ impl std::str::FromStr for ObjectKind {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
             "led" => Ok(Self::LED),
+            "dimmablelight" | "dimmable" => Ok(Self::DimmableLight),
+            "rgblight" | "rgb" => Ok(Self::RgbLight),
+            "temperaturesensor" | "temperature" => Ok(Self::TemperatureSensor),
+            "humiditysensor" | "humidity" => Ok(Self::HumiditySensor),
+            "motionsensor" | "motion" => Ok(Self::MotionSensor),
+            "contactsensor" | "contact" => Ok(Self::ContactSensor),
             _ => Err("unknown object kind"),
         }
     }
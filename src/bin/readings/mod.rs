@@ -0,0 +1,221 @@
+//! Values measured by sensors, stored as one time series per device.
+//!
+//! Entries are keyed by device id then unix time in seconds, both big endian
+//! so that the readings of a device are contiguous and ordered by time.
+
+use std::convert::TryInto;
+
+#[derive(Debug)]
+pub enum ReadingError {
+    Sled(sled::Error),
+}
+impl From<sled::Error> for ReadingError {
+    fn from(err: sled::Error) -> Self {
+        Self::Sled(err)
+    }
+}
+
+/// Readings of a time span, `value` being their mean
+pub struct Summary {
+    /// Start of the span
+    pub time: i64,
+    pub value: f64,
+    pub min: f64,
+    pub max: f64,
+    pub count: u32,
+}
+
+fn key(object_id: u32, time: i64) -> Vec<u8> {
+    let mut key = object_id.to_be_bytes().to_vec();
+    // Readings from before 1970 are of no interest, they are clamped to keep the order
+    key.extend_from_slice(&(std::cmp::max(time, 0) as u64).to_be_bytes());
+    key
+}
+
+fn decode(key: &[u8], value: &[u8]) -> Option<(u32, i64, f64)> {
+    let object_id = u32::from_be_bytes(key.get(..4)?.try_into().ok()?);
+    let time = u64::from_be_bytes(key.get(4..12)?.try_into().ok()?) as i64;
+    let value = f64::from_be_bytes(value.try_into().ok()?);
+    Some((object_id, time, value))
+}
+
+#[derive(Clone)]
+pub struct Readings {
+    readings: sled::Tree,
+}
+
+impl Readings {
+    pub fn open(db: &sled::Db) -> Result<Readings, ReadingError> {
+        Ok(Readings {
+            readings: db.open_tree("readings")?,
+        })
+    }
+
+    /// Records a value, replacing the one recorded at the same second if any
+    pub fn record(&self, object_id: u32, time: i64, value: f64) -> Result<(), ReadingError> {
+        self.readings.insert(key(object_id, time), &value.to_be_bytes()[..])?;
+        Ok(())
+    }
+
+    /// Readings of the device between `from` and `to` included, oldest first,
+    /// at most `limit` of them
+    pub fn range(&self, object_id: u32, from: i64, to: i64, limit: usize) -> Result<Vec<(i64, f64)>, ReadingError> {
+        self.iter(object_id, from, to).take(limit).collect()
+    }
+
+    /// Readings of the device between `from` and `to` included summarized as
+    /// by `downsample`, without keeping every reading in memory
+    pub fn summaries(&self, object_id: u32, from: i64, to: i64, step: i64, limit: usize) -> Result<Vec<Summary>, ReadingError> {
+        let mut error = None;
+        let readings = self.iter(object_id, from, to).scan(&mut error, |error, entry| match entry {
+            Ok(reading) => Some(reading),
+            Err(e) => {
+                **error = Some(e);
+                None
+            }
+        });
+        let summaries = downsample(readings, from, step, limit);
+        match error {
+            Some(e) => Err(e),
+            None => Ok(summaries),
+        }
+    }
+
+    fn iter(&self, object_id: u32, from: i64, to: i64) -> impl Iterator<Item = Result<(i64, f64), ReadingError>> + '_ {
+        // sled refuses a range ending before its start
+        let readings = if from > to { None } else { Some(self.readings.range(key(object_id, from)..=key(object_id, to))) };
+        readings.into_iter().flatten().filter_map(|entry| match entry {
+            Ok((key, value)) => decode(&key, &value).map(|(_, time, value)| Ok((time, value))),
+            Err(e) => Some(Err(e.into())),
+        })
+    }
+
+    /// Removes at most `limit` readings older than `before`, of any device.
+    ///
+    /// Returns the number of readings removed, none are left once it is below `limit`
+    pub fn prune(&self, before: i64, limit: usize) -> Result<usize, ReadingError> {
+        let mut pruned = 0;
+        let mut next = Some(key(0, 0));
+        while let Some(start) = next.take() {
+            for entry in self.readings.range(start..) {
+                let (key, value) = entry?;
+                let (object_id, time) = match decode(&key, &value) {
+                    Some((object_id, time, _)) => (object_id, time),
+                    None => continue,
+                };
+                // The older readings of the next device come after the recent ones of this one
+                if time >= before {
+                    next = object_id.checked_add(1).map(|id| self::key(id, 0));
+                    break;
+                }
+                if pruned == limit {
+                    return Ok(pruned);
+                }
+                self.readings.remove(key)?;
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Removes the readings of removed devices
+    pub fn forget(&self, devices: &[u32]) -> Result<(), ReadingError> {
+        for &object_id in devices {
+            for entry in self.readings.range(key(object_id, 0)..=key(object_id, i64::max_value())) {
+                let (key, _) = entry?;
+                self.readings.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Groups readings, oldest first, in spans of `step` seconds aligned on `from`.
+///
+/// Empty spans are skipped, the readings after the first `limit` spans are not read
+pub fn downsample(readings: impl IntoIterator<Item = (i64, f64)>, from: i64, step: i64, limit: usize) -> Vec<Summary> {
+    let mut summaries = Vec::new();
+    let mut current: Option<Summary> = None;
+    for (time, value) in readings {
+        let start = from + (time - from) / step * step;
+        match &mut current {
+            Some(summary) if summary.time == start => {
+                summary.value += value;
+                summary.min = summary.min.min(value);
+                summary.max = summary.max.max(value);
+                summary.count += 1;
+                continue;
+            }
+            _ => (),
+        }
+        if let Some(summary) = current.take() {
+            summaries.push(average(summary));
+        }
+        if summaries.len() == limit {
+            return summaries;
+        }
+        current = Some(Summary {
+            time: start,
+            value,
+            min: value,
+            max: value,
+            count: 1,
+        });
+    }
+    summaries.extend(current.map(average));
+    summaries
+}
+
+fn average(summary: Summary) -> Summary {
+    Summary {
+        value: summary.value / f64::from(summary.count),
+        ..summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(summaries: &[Summary]) -> Vec<(i64, f64, f64, f64, u32)> {
+        summaries.iter().map(|s| (s.time, s.value, s.min, s.max, s.count)).collect()
+    }
+
+    #[test]
+    fn downsample_spans() {
+        let readings = [(100, 1.0), (105, 3.0), (109, 2.0), (110, 5.0), (135, -1.0), (139, 1.0)];
+        assert_eq!(
+            spans(&downsample(readings.iter().cloned(), 100, 10, 10)),
+            vec![(100, 2.0, 1.0, 3.0, 3), (110, 5.0, 5.0, 5.0, 1), (130, 0.0, -1.0, 1.0, 2)]
+        );
+        // Spans are aligned on `from`, not on the first reading
+        assert_eq!(spans(&downsample(readings[3..].iter().cloned(), 95, 20, 10)), vec![(95, 5.0, 5.0, 5.0, 1), (135, 0.0, -1.0, 1.0, 2)]);
+        assert!(downsample(Vec::new(), 0, 10, 10).is_empty());
+        // Stops at the limit, the last span kept is complete
+        assert_eq!(spans(&downsample(readings.iter().cloned(), 100, 10, 2)), vec![(100, 2.0, 1.0, 3.0, 3), (110, 5.0, 5.0, 5.0, 1)]);
+        assert!(downsample(readings.iter().cloned(), 100, 10, 0).is_empty());
+    }
+
+    #[test]
+    fn prune_old_readings() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::Db::open(dir.path()).unwrap();
+        let readings = Readings::open(&db).unwrap();
+        for object_id in 0..3 {
+            for time in 0..10 {
+                readings.record(object_id, time * 10, time as f64).unwrap();
+            }
+        }
+        assert_eq!(readings.prune(50, 4).unwrap(), 4);
+        assert_eq!(readings.prune(50, 100).unwrap(), 11);
+        assert_eq!(readings.prune(50, 100).unwrap(), 0);
+        for object_id in 0..3 {
+            let times: Vec<i64> = readings.range(object_id, 0, 100, 100).unwrap().into_iter().map(|(time, _)| time).collect();
+            assert_eq!(times, vec![50, 60, 70, 80, 90]);
+        }
+        assert_eq!(readings.range(0, 0, 100, 2).unwrap().len(), 2);
+        assert!(readings.range(0, 100, 0, 10).unwrap().is_empty());
+        let summaries = readings.summaries(0, 50, 100, 20, 10).unwrap();
+        assert_eq!(spans(&summaries), vec![(50, 5.5, 5.0, 6.0, 2), (70, 7.5, 7.0, 8.0, 2), (90, 9.0, 9.0, 9.0, 1)]);
+    }
+}
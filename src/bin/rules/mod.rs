@@ -0,0 +1,246 @@
+//! Commands dispatched in reaction to the events of the server.
+//!
+//! A rule may trigger itself through the commands it sends, directly or
+//! through other rules. Events caused by rules carry the number of rules that
+//! fired in a row, and rules stop firing past `MAX_DEPTH`.
+//!
+//! The rules fired by an event and by the events they cause share a budget of
+//! `MAX_ACTIONS` actions, so that rules fanning out to many others stop too.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::events::{Event, EventKind};
+use crate::objects::DeviceState;
+use crate::scenes::SceneAction;
+use crate::{HomeServer, Write};
+
+/// Maximum number of rules firing one after the other
+pub const MAX_DEPTH: u32 = 8;
+
+/// Maximum number of actions run by the rules fired from one event
+pub const MAX_ACTIONS: u32 = 64;
+
+/// Actions left to the rules fired from one event
+#[derive(Clone, Debug)]
+pub struct Budget(Arc<AtomicU32>);
+
+impl Budget {
+    fn new() -> Budget {
+        Budget(Arc::new(AtomicU32::new(MAX_ACTIONS)))
+    }
+
+    /// Uses up one action, false once none is left
+    fn take(&self) -> bool {
+        let mut left = self.0.load(Ordering::SeqCst);
+        while left > 0 {
+            match self.0.compare_exchange(left, left - 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(current) => left = current,
+            }
+        }
+        false
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Trigger {
+    /// The state of the device changed, to `state` if set
+    StateChanged { object_id: u32, state: Option<DeviceState> },
+    CommandExecuted { object_id: u32 },
+    ActionnerOnline { actionner_id: u32 },
+    ActionnerOffline { actionner_id: u32 },
+}
+
+impl Trigger {
+    pub fn matches(&self, event: &EventKind) -> bool {
+        match (self, event) {
+            (Trigger::StateChanged { object_id, state }, EventKind::StateChanged { object_id: id, state: new }) => {
+                object_id == id && state.map(|s| s == *new).unwrap_or(true)
+            }
+            (Trigger::CommandExecuted { object_id }, EventKind::CommandExecuted { object_id: id, .. }) => object_id == id,
+            (Trigger::ActionnerOnline { actionner_id }, EventKind::ActionnerOnline { actionner_id: id }) => actionner_id == id,
+            (Trigger::ActionnerOffline { actionner_id }, EventKind::ActionnerOffline { actionner_id: id }) => actionner_id == id,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Rule {
+    pub trigger: Trigger,
+    pub actions: Vec<SceneAction>,
+    /// Logged as a warning when the rule fires, if not empty
+    pub log: String,
+}
+
+#[derive(Debug)]
+pub enum RuleError {
+    Sled(sled::Error),
+    Serde(bincode::Error),
+    NotFound,
+}
+impl From<sled::Error> for RuleError {
+    fn from(err: sled::Error) -> Self {
+        Self::Sled(err)
+    }
+}
+impl From<bincode::Error> for RuleError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Serde(err)
+    }
+}
+
+/// Named rules, stored next to the devices
+pub struct Rules {
+    rules: sled::Tree,
+}
+
+impl Rules {
+    pub fn open(db: &sled::Db) -> Result<Rules, RuleError> {
+        Ok(Rules {
+            rules: db.open_tree("rules")?,
+        })
+    }
+
+    /// Creates the rule, replacing it if it already exists
+    pub fn set(&self, name: &str, rule: &Rule) -> Result<(), RuleError> {
+        self.rules.insert(name.as_bytes(), bincode::serialize(rule)?)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), RuleError> {
+        match self.rules.remove(name.as_bytes())? {
+            Some(_) => Ok(()),
+            None => Err(RuleError::NotFound),
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<(String, Rule)>, RuleError> {
+        let mut list = Vec::new();
+        for entry in self.rules.iter() {
+            let (name, rule) = entry?;
+            list.push((String::from_utf8_lossy(&name).into_owned(), bincode::deserialize(&rule)?));
+        }
+        Ok(list)
+    }
+
+    pub fn tree(&self) -> &sled::Tree {
+        &self.rules
+    }
+
+    /// Writes removing the rules triggered by removed devices and the actions
+    /// on removed devices from the other rules, applied with the removal of the devices
+    pub fn forget(&self, devices: &[u32]) -> Result<Vec<Write>, RuleError> {
+        let mut writes = Vec::new();
+        for (name, mut rule) in self.list()? {
+            let removed_trigger = match rule.trigger {
+                Trigger::StateChanged { object_id, .. } | Trigger::CommandExecuted { object_id } => devices.contains(&object_id),
+                Trigger::ActionnerOnline { .. } | Trigger::ActionnerOffline { .. } => false,
+            };
+            if removed_trigger {
+                writes.push((name.into_bytes(), None));
+                continue;
+            }
+            let before = rule.actions.len();
+            rule.actions.retain(|action| !devices.contains(&action.object_id));
+            if rule.actions.len() != before {
+                writes.push((name.into_bytes(), Some(bincode::serialize(&rule)?)));
+            }
+        }
+        Ok(writes)
+    }
+
+    /// The rules that fire on the event
+    pub fn matching(&self, event: &EventKind) -> Result<Vec<(String, Rule)>, RuleError> {
+        Ok(self.list()?.into_iter().filter(|(_, rule)| rule.trigger.matches(event)).collect())
+    }
+}
+
+async fn fire(server: HomeServer, name: String, rule: Rule, depth: u32, budget: Budget) {
+    if !rule.log.is_empty() {
+        tracing::warn!("Rule {}: {}", name, rule.log);
+    }
+    for action in &rule.actions {
+        if !budget.take() {
+            tracing::warn!("Rule {} stopped, {} actions already ran from the same event", name, MAX_ACTIONS);
+            return;
+        }
+        if let Err(status) = server.run_command_with_depth(action.object_id, &action.command, depth, Some(budget.clone())).await {
+            tracing::warn!("Rule {} could not command {}: {}", name, action.object_id, status.message());
+        }
+    }
+}
+
+/// Fires the rules matching each event received, forever
+pub async fn run(server: HomeServer, mut events: mpsc::Receiver<Event>) {
+    while let Some(event) = events.recv().await {
+        if event.depth >= MAX_DEPTH {
+            tracing::warn!("Not evaluating rules on {:?}, {} rules fired in a row", event.kind, event.depth);
+            continue;
+        }
+        let rules = match server.devices.lock().await.rules.matching(&event.kind) {
+            Ok(rules) => rules,
+            Err(e) => {
+                tracing::warn!("Could not read rules: {:?}", e);
+                continue;
+            }
+        };
+        let budget = event.budget.unwrap_or_else(Budget::new);
+        for (name, rule) in rules {
+            tokio::spawn(fire(server.clone(), name, rule, event.depth + 1, budget.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_is_shared() {
+        let budget = Budget::new();
+        let other = budget.clone();
+        for _ in 0..MAX_ACTIONS / 2 {
+            assert!(budget.take());
+            assert!(other.take());
+        }
+        assert!(!budget.take());
+        assert!(!other.take());
+    }
+
+    #[test]
+    fn forget_removed_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::Db::open(dir.path()).unwrap();
+        let rules = Rules::open(&db).unwrap();
+        let action = |object_id| SceneAction { object_id, command: Vec::new() };
+        let triggered = Rule {
+            trigger: Trigger::CommandExecuted { object_id: 1 },
+            actions: vec![action(2)],
+            log: String::new(),
+        };
+        let acting = Rule {
+            trigger: Trigger::ActionnerOffline { actionner_id: 1 },
+            actions: vec![action(1), action(2)],
+            log: String::new(),
+        };
+        rules.set("triggered", &triggered).unwrap();
+        rules.set("acting", &acting).unwrap();
+        let writes = rules.forget(&[1]).unwrap();
+        assert_eq!(writes.len(), 2);
+        for (key, value) in writes {
+            match value {
+                Some(value) => rules.rules.insert(key, value).unwrap(),
+                None => rules.rules.remove(key).unwrap(),
+            };
+        }
+        let list = rules.list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].0, "acting");
+        let targets: Vec<u32> = list[0].1.actions.iter().map(|action| action.object_id).collect();
+        assert_eq!(targets, vec![2]);
+    }
+}
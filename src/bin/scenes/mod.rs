@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Write;
+
+/// Named presets of commands applied together, stored next to the devices
+pub struct Scenes {
+    scenes: sled::Tree,
+}
+
+/// A command to send to a device, `command` is encoded as in `CommandRequest`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SceneAction {
+    pub object_id: u32,
+    pub command: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Sled(sled::Error),
+    Serde(bincode::Error),
+    NotFound,
+}
+impl From<sled::Error> for SceneError {
+    fn from(err: sled::Error) -> Self {
+        Self::Sled(err)
+    }
+}
+impl From<bincode::Error> for SceneError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Serde(err)
+    }
+}
+
+impl Scenes {
+    pub fn open(db: &sled::Db) -> Result<Scenes, SceneError> {
+        Ok(Scenes {
+            scenes: db.open_tree("scenes")?,
+        })
+    }
+
+    /// Creates the scene, replacing it if it already exists
+    pub fn set(&self, name: &str, actions: &[SceneAction]) -> Result<(), SceneError> {
+        self.scenes.insert(name.as_bytes(), bincode::serialize(actions)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Vec<SceneAction>, SceneError> {
+        match self.scenes.get(name.as_bytes())? {
+            Some(actions) => Ok(bincode::deserialize(&actions)?),
+            None => Err(SceneError::NotFound),
+        }
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), SceneError> {
+        match self.scenes.remove(name.as_bytes())? {
+            Some(_) => Ok(()),
+            None => Err(SceneError::NotFound),
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<(String, Vec<SceneAction>)>, SceneError> {
+        let mut list = Vec::new();
+        for entry in self.scenes.iter() {
+            let (name, actions) = entry?;
+            list.push((String::from_utf8_lossy(&name).into_owned(), bincode::deserialize(&actions)?));
+        }
+        Ok(list)
+    }
+
+    pub fn tree(&self) -> &sled::Tree {
+        &self.scenes
+    }
+
+    /// Writes removing the actions of removed devices from every scene,
+    /// applied with the removal of the devices
+    pub fn forget(&self, devices: &[u32]) -> Result<Vec<Write>, SceneError> {
+        let mut writes = Vec::new();
+        for (name, mut actions) in self.list()? {
+            let before = actions.len();
+            actions.retain(|action| !devices.contains(&action.object_id));
+            if actions.len() != before {
+                writes.push((name.into_bytes(), Some(bincode::serialize(&actions)?)));
+            }
+        }
+        Ok(writes)
+    }
+}
@@ -0,0 +1,503 @@
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::prelude::*;
+
+use crate::{HomeServer, Write};
+
+/// Source of the current time, replaced in tests to run schedules without waiting
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+}
+
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// Years searched for the next run of a cron expression, leap days come back
+/// within 8 years
+const SEARCH_YEARS: i32 = 8;
+
+/// Longest interval between two runs, ten years
+const MAX_INTERVAL: u64 = 10 * 366 * 24 * 3600;
+
+/// Next run of a schedule whose next run can't be computed, it never runs again
+pub const DISABLED: i64 = i64::max_value();
+
+/// Schedules that are late by more than this are skipped instead of run, for
+/// example when the server was down at the time
+const GRACE_SECONDS: i64 = 60;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Trigger {
+    /// Runs every `seconds` seconds
+    Interval { seconds: u64 },
+    /// Runs when the local time matches a five fields cron expression
+    Cron { expression: String },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Schedule {
+    pub object_id: u32,
+    /// Encoded as in `CommandRequest`
+    pub command: Vec<u8>,
+    pub trigger: Trigger,
+    /// Unix timestamp of the next run
+    pub next_run: i64,
+}
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    Sled(sled::Error),
+    Serde(bincode::Error),
+    InvalidTrigger(String),
+    NotFound,
+}
+impl From<sled::Error> for ScheduleError {
+    fn from(err: sled::Error) -> Self {
+        Self::Sled(err)
+    }
+}
+impl From<bincode::Error> for ScheduleError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Serde(err)
+    }
+}
+
+/// Set of allowed values of a cron field, as a bit mask
+#[derive(Clone, Copy)]
+struct CronField(u64);
+
+impl CronField {
+    fn parse(field: &str, min: u32, max: u32) -> Result<CronField, String> {
+        let mut mask = 0;
+        for part in field.split(',') {
+            let (range, step) = match part.find('/') {
+                Some(idx) => {
+                    let step: u32 = part[idx + 1..].parse().map_err(|_| format!("invalid step in {}", part))?;
+                    if step == 0 {
+                        return Err(format!("invalid step in {}", part));
+                    }
+                    (&part[..idx], step)
+                }
+                None => (part, 1),
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else {
+                match range.find('-') {
+                    Some(idx) => (
+                        range[..idx].parse().map_err(|_| format!("invalid range {}", range))?,
+                        range[idx + 1..].parse().map_err(|_| format!("invalid range {}", range))?,
+                    ),
+                    None => {
+                        let value = range.parse().map_err(|_| format!("invalid value {}", range))?;
+                        (value, value)
+                    }
+                }
+            };
+            if start < min || end > max || start > end {
+                return Err(format!("{} out of range {}-{}", part, min, max));
+            }
+            for value in (start..=end).step_by(step as usize) {
+                mask |= 1 << value;
+            }
+        }
+        Ok(CronField(mask))
+    }
+
+    fn contains(self, value: u32) -> bool {
+        self.0 & (1 << value) != 0
+    }
+
+    /// Smallest allowed value from `from` to `max`
+    fn next_value(self, from: u32, max: u32) -> Option<u32> {
+        (from..=max).find(|&value| self.contains(value))
+    }
+
+    fn is_full(self, min: u32, max: u32) -> bool {
+        (min..=max).all(|v| self.contains(v))
+    }
+}
+
+/// A parsed `minute hour day-of-month month day-of-week` expression
+pub struct Cron {
+    minute: CronField,
+    hour: CronField,
+    day_of_month: CronField,
+    month: CronField,
+    day_of_week: CronField,
+}
+
+impl std::str::FromStr for Cron {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err("expected minute, hour, day of month, month and day of week".to_owned());
+        }
+        let mut day_of_week = CronField::parse(fields[4], 0, 7)?;
+        // Both 0 and 7 are sunday
+        if day_of_week.contains(7) {
+            day_of_week.0 |= 1;
+        }
+        Ok(Cron {
+            minute: CronField::parse(fields[0], 0, 59)?,
+            hour: CronField::parse(fields[1], 0, 23)?,
+            day_of_month: CronField::parse(fields[2], 1, 31)?,
+            month: CronField::parse(fields[3], 1, 12)?,
+            day_of_week,
+        })
+    }
+}
+
+impl Cron {
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day_of_month = self.day_of_month.contains(date.day());
+        let day_of_week = self.day_of_week.contains(date.weekday().num_days_from_sunday());
+        // As in cron, when both days are restricted either one matching is enough
+        if self.day_of_month.is_full(1, 31) || self.day_of_week.is_full(0, 6) {
+            day_of_month && day_of_week
+        } else {
+            day_of_month || day_of_week
+        }
+    }
+
+    /// First matching minute strictly after `after`.
+    ///
+    /// The fields are matched from the month down to the minute, skipping the
+    /// whole month, day or hour that doesn't match. The search stops after
+    /// `SEARCH_YEARS` years, which always reaches a leap day.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let last_year = start.year() + SEARCH_YEARS;
+        let mut date = start.date();
+        let (mut hour, mut minute) = (start.hour(), start.minute());
+        while date.year() <= last_year {
+            if !self.month.contains(date.month()) {
+                date = match date.month() {
+                    12 => NaiveDate::from_ymd_opt(date.year() + 1, 1, 1)?,
+                    month => NaiveDate::from_ymd_opt(date.year(), month + 1, 1)?,
+                };
+                hour = 0;
+                minute = 0;
+                continue;
+            }
+            if self.day_matches(date) {
+                while let Some(h) = self.hour.next_value(hour, 23) {
+                    let from = if h == hour { minute } else { 0 };
+                    match self.minute.next_value(from, 59) {
+                        Some(m) => {
+                            let time = date.and_hms_opt(h, m, 0)?;
+                            // Times skipped or repeated by a daylight saving change
+                            let candidates = [Local.from_local_datetime(&time).earliest(), Local.from_local_datetime(&time).latest()];
+                            if let Some(next) = candidates.iter().flatten().find(|next| **next > after) {
+                                return Some(*next);
+                            }
+                            hour = h;
+                            minute = m + 1;
+                        }
+                        None => {
+                            hour = h + 1;
+                            minute = 0;
+                        }
+                    }
+                    if minute > 59 {
+                        hour += 1;
+                        minute = 0;
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+            hour = 0;
+            minute = 0;
+        }
+        None
+    }
+}
+
+impl Trigger {
+    pub fn validate(&self) -> Result<(), ScheduleError> {
+        match self {
+            Trigger::Interval { seconds: 0 } => Err(ScheduleError::InvalidTrigger("interval can't be 0".to_owned())),
+            Trigger::Interval { seconds } if *seconds > MAX_INTERVAL => {
+                Err(ScheduleError::InvalidTrigger(format!("interval can't be over {} seconds", MAX_INTERVAL)))
+            }
+            Trigger::Interval { .. } => Ok(()),
+            Trigger::Cron { expression } => expression.parse::<Cron>().map(|_| ()).map_err(ScheduleError::InvalidTrigger),
+        }
+    }
+
+    /// Unix timestamp of the first run after `now`
+    pub fn next_run(&self, now: DateTime<Local>) -> Result<i64, ScheduleError> {
+        match self {
+            Trigger::Interval { seconds } => i64::try_from(*seconds)
+                .ok()
+                .and_then(|seconds| now.timestamp().checked_add(seconds))
+                .ok_or_else(|| ScheduleError::InvalidTrigger(format!("interval of {} seconds is too long", seconds))),
+            Trigger::Cron { expression } => {
+                let cron: Cron = expression.parse().map_err(ScheduleError::InvalidTrigger)?;
+                match cron.next_after(now) {
+                    Some(next) => Ok(next.timestamp()),
+                    None => Err(ScheduleError::InvalidTrigger(format!("{} never matches", expression))),
+                }
+            }
+        }
+    }
+}
+
+/// Schedules persisted next to the devices
+pub struct Schedules {
+    schedules: sled::Tree,
+    next_id_to_assign: u32,
+}
+
+impl Schedules {
+    pub fn open(db: &sled::Db) -> Result<Schedules, ScheduleError> {
+        let schedules = db.open_tree("schedules")?;
+        let mut next_id_to_assign = 0;
+        for entry in schedules.iter() {
+            let (id, _) = entry?;
+            let id: u32 = bincode::deserialize(&id)?;
+            next_id_to_assign = std::cmp::max(next_id_to_assign, id + 1);
+        }
+        Ok(Schedules {
+            schedules,
+            next_id_to_assign,
+        })
+    }
+
+    /// Returns the id of the schedule and its first run
+    pub fn add(&mut self, object_id: u32, command: Vec<u8>, trigger: Trigger, now: DateTime<Local>) -> Result<(u32, i64), ScheduleError> {
+        trigger.validate()?;
+        let schedule = Schedule {
+            object_id,
+            command,
+            next_run: trigger.next_run(now)?,
+            trigger,
+        };
+        let id = self.next_id_to_assign;
+        self.next_id_to_assign += 1;
+        self.schedules.insert(bincode::serialize(&id)?, bincode::serialize(&schedule)?)?;
+        Ok((id, schedule.next_run))
+    }
+
+    pub fn remove(&self, id: u32) -> Result<(), ScheduleError> {
+        match self.schedules.remove(bincode::serialize(&id)?)? {
+            Some(_) => Ok(()),
+            None => Err(ScheduleError::NotFound),
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<(u32, Schedule)>, ScheduleError> {
+        let mut list = Vec::new();
+        for entry in self.schedules.iter() {
+            let (id, schedule) = entry?;
+            list.push((bincode::deserialize(&id)?, bincode::deserialize(&schedule)?));
+        }
+        list.sort_by_key(|(id, _)| *id);
+        Ok(list)
+    }
+
+    pub fn tree(&self) -> &sled::Tree {
+        &self.schedules
+    }
+
+    /// Writes removing the schedules of removed devices, applied with the removal of the devices
+    pub fn forget(&self, devices: &[u32]) -> Result<Vec<Write>, ScheduleError> {
+        let mut writes = Vec::new();
+        for (id, schedule) in self.list()? {
+            if devices.contains(&schedule.object_id) {
+                writes.push((bincode::serialize(&id)?, None));
+            }
+        }
+        Ok(writes)
+    }
+
+    /// Returns the schedules due at `now` and moves them to their next run.
+    ///
+    /// The next run is saved before the command is sent so that a crash can't
+    /// run a schedule twice. A schedule whose next run can't be computed is
+    /// disabled, the others are still run.
+    pub fn take_due(&self, now: DateTime<Local>) -> Result<Vec<(u32, Schedule)>, ScheduleError> {
+        let mut due = Vec::new();
+        for (id, mut schedule) in self.list()? {
+            if schedule.next_run > now.timestamp() {
+                continue;
+            }
+            let late = now.timestamp() - schedule.next_run;
+            let run = schedule.clone();
+            schedule.next_run = match schedule.trigger.next_run(now) {
+                Ok(next_run) => next_run,
+                Err(e) => {
+                    tracing::warn!("Disabled schedule {}, its next run is unknown: {:?}", id, e);
+                    DISABLED
+                }
+            };
+            self.schedules.insert(bincode::serialize(&id)?, bincode::serialize(&schedule)?)?;
+            if late > GRACE_SECONDS {
+                tracing::info!(
+                    "Skipped schedule {} planned at {}",
+                    id,
+                    Local.timestamp(run.next_run, 0).to_rfc3339()
+                );
+            } else {
+                due.push((id, run));
+            }
+        }
+        Ok(due)
+    }
+}
+
+/// Runs the schedules that are due at the time given by the clock
+pub async fn tick(server: &HomeServer, now: DateTime<Local>) {
+    let due = match server.devices.lock().await.schedules.take_due(now) {
+        Ok(due) => due,
+        Err(e) => {
+            tracing::warn!("Could not read schedules: {:?}", e);
+            return;
+        }
+    };
+    for (id, schedule) in due {
+        match server.run_command(schedule.object_id, &schedule.command).await {
+            Ok(result) => tracing::info!("Ran schedule {} on {}, success: {}", id, schedule.object_id, result.success),
+            Err(status) => tracing::warn!("Schedule {} on {} failed: {}", id, schedule.object_id, status.message()),
+        }
+    }
+}
+
+/// Checks the schedules every second, forever
+pub async fn run(server: HomeServer, clock: Arc<dyn Clock>) {
+    let mut interval = tokio::timer::Interval::new_interval(std::time::Duration::from_secs(1));
+    loop {
+        interval.next().await;
+        tick(&server, clock.now()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A clock only moving when told to
+    struct FakeClock(Mutex<DateTime<Local>>);
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Local> {
+            *self.0.lock().unwrap()
+        }
+    }
+    impl FakeClock {
+        fn advance(&self, seconds: i64) {
+            let mut now = self.0.lock().unwrap();
+            *now = *now + chrono::Duration::seconds(seconds);
+        }
+    }
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.ymd(year, month, day).and_hms(hour, minute, 0)
+    }
+
+    fn next(expression: &str, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        expression.parse::<Cron>().unwrap().next_after(after)
+    }
+
+    fn schedules(dir: &std::path::Path) -> (sled::Db, Schedules) {
+        let db = sled::Db::open(dir).unwrap();
+        let schedules = Schedules::open(&db).unwrap();
+        (db, schedules)
+    }
+
+    fn due_ids(schedules: &Schedules, clock: &FakeClock) -> Vec<u32> {
+        schedules.take_due(clock.now()).unwrap().into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn cron_next_run() {
+        let after = local(2023, 3, 3, 10, 7);
+        assert_eq!(next("*/15 * * * *", after), Some(local(2023, 3, 3, 10, 15)));
+        assert_eq!(next("7 10 * * *", after), Some(local(2023, 3, 4, 10, 7)));
+        assert_eq!(next("30 9 * * 1-5", after), Some(local(2023, 3, 6, 9, 30)));
+        assert_eq!(next("0 0 1 1 *", after), Some(local(2024, 1, 1, 0, 0)));
+        assert_eq!(next("0 22 31 * *", after), Some(local(2023, 3, 31, 22, 0)));
+        // Either day matching is enough when both are restricted
+        assert_eq!(next("0 12 13 * 0", after), Some(local(2023, 3, 5, 12, 0)));
+    }
+
+    #[test]
+    fn cron_reaches_leap_days() {
+        assert_eq!(next("0 0 29 2 *", local(2023, 3, 1, 0, 0)), Some(local(2024, 2, 29, 0, 0)));
+        assert_eq!(next("0 0 29 2 *", local(2024, 2, 29, 0, 0)), Some(local(2028, 2, 29, 0, 0)));
+        // 2100 is not a leap year
+        assert_eq!(next("0 0 29 2 *", local(2096, 3, 1, 0, 0)), Some(local(2104, 2, 29, 0, 0)));
+        assert_eq!(next("0 0 30 2 *", local(2023, 3, 1, 0, 0)), None);
+    }
+
+    #[test]
+    fn interval_next_run() {
+        let now = local(2023, 3, 3, 10, 7);
+        assert_eq!(Trigger::Interval { seconds: 90 }.next_run(now).unwrap(), now.timestamp() + 90);
+        assert!(Trigger::Interval { seconds: 0 }.validate().is_err());
+        assert!(Trigger::Interval { seconds: MAX_INTERVAL }.validate().is_ok());
+        assert!(Trigger::Interval { seconds: MAX_INTERVAL + 1 }.validate().is_err());
+        assert!(Trigger::Interval { seconds: u64::max_value() }.next_run(now).is_err());
+        assert!(Trigger::Interval { seconds: i64::max_value() as u64 }.next_run(now).is_err());
+    }
+
+    #[test]
+    fn due_schedules_run_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_db, mut schedules) = schedules(dir.path());
+        let clock = FakeClock(Mutex::new(local(2023, 3, 3, 10, 7)));
+        let (interval, _) = schedules.add(1, Vec::new(), Trigger::Interval { seconds: 60 }, clock.now()).unwrap();
+        let expression = "8 10 * * *".to_owned();
+        let (cron, next_run) = schedules.add(2, Vec::new(), Trigger::Cron { expression }, clock.now()).unwrap();
+        assert_eq!(next_run, local(2023, 3, 3, 10, 8).timestamp());
+        assert!(due_ids(&schedules, &clock).is_empty());
+        clock.advance(60);
+        assert_eq!(due_ids(&schedules, &clock), vec![interval, cron]);
+        assert!(due_ids(&schedules, &clock).is_empty());
+        clock.advance(61);
+        assert_eq!(due_ids(&schedules, &clock), vec![interval]);
+    }
+
+    #[test]
+    fn late_schedules_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_db, mut schedules) = schedules(dir.path());
+        let clock = FakeClock(Mutex::new(local(2023, 3, 3, 10, 7)));
+        let (id, _) = schedules.add(1, Vec::new(), Trigger::Interval { seconds: 60 }, clock.now()).unwrap();
+        clock.advance(60 + GRACE_SECONDS + 1);
+        assert!(due_ids(&schedules, &clock).is_empty());
+        let (_, schedule) = schedules.list().unwrap().pop().unwrap();
+        assert_eq!(schedule.next_run, clock.now().timestamp() + 60);
+        clock.advance(60);
+        assert_eq!(due_ids(&schedules, &clock), vec![id]);
+    }
+
+    #[test]
+    fn failing_schedule_is_disabled_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_db, mut schedules) = schedules(dir.path());
+        let clock = FakeClock(Mutex::new(local(2023, 3, 3, 10, 7)));
+        let (valid, _) = schedules.add(1, Vec::new(), Trigger::Interval { seconds: 60 }, clock.now()).unwrap();
+        // Written as an older server could have, the expression never matches
+        let broken = Schedule {
+            object_id: 2,
+            command: Vec::new(),
+            trigger: Trigger::Cron { expression: "0 0 30 2 *".to_owned() },
+            next_run: clock.now().timestamp() + 60,
+        };
+        schedules.schedules.insert(bincode::serialize(&7u32).unwrap(), bincode::serialize(&broken).unwrap()).unwrap();
+        clock.advance(60);
+        assert_eq!(due_ids(&schedules, &clock), vec![valid, 7]);
+        let list = schedules.list().unwrap();
+        assert_eq!(list[1].1.next_run, DISABLED);
+        clock.advance(60);
+        assert_eq!(due_ids(&schedules, &clock), vec![valid]);
+    }
+}
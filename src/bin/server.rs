@@ -1,13 +1,29 @@
 use std::{collections::{HashMap, HashSet}, sync::Arc};
 use tokio::sync::{mpsc, Mutex};
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{transport::{Certificate, Identity, Server, ServerTlsConfig}, Request, Response, Status};
 use tokio::prelude::*;
 use serde::{Serialize, Deserialize};
+use sled::Transactional;
+use tower_service::Service;
 
 mod objects;
 mod commands;
-use commands::ArduinoCommand;
-use objects::{Object, ObjectKind, Protocol, ActionnerId};
+mod config;
+mod manifest;
+mod backup;
+mod migrations;
+mod groups;
+mod scenes;
+mod scheduler;
+mod events;
+mod rules;
+mod auth;
+mod readings;
+mod arduino;
+use commands::{ArduinoCommand, SshCommand};
+use objects::{Object, ObjectKind, Protocol, ActionnerId, DeviceState};
+use config::{Config, ProtocolSettings};
+use structopt::StructOpt;
 
 pub mod home_manager {
     tonic::include_proto!("home_manager");
@@ -18,9 +34,349 @@ use home_manager::{
     ListDeviceReply, ListDeviceRequest,
 };
 
+impl From<DeviceState> for home_manager::Status {
+    fn from(state: DeviceState) -> Self {
+        match state {
+            DeviceState::Unknown => home_manager::Status::Unknown,
+            DeviceState::On => home_manager::Status::On,
+            DeviceState::Off => home_manager::Status::Off,
+        }
+    }
+}
+
+fn group_status(err: groups::GroupError) -> Status {
+    match err {
+        groups::GroupError::NotFound => Status::new(tonic::Code::NotFound, "group not found"),
+        groups::GroupError::AlreadyExists => Status::new(tonic::Code::AlreadyExists, "group already exists"),
+        e => {
+            tracing::warn!("Internal error in groups: {:?}", e);
+            Status::new(tonic::Code::Internal, "")
+        }
+    }
+}
+
+fn scene_status(err: scenes::SceneError) -> Status {
+    match err {
+        scenes::SceneError::NotFound => Status::new(tonic::Code::NotFound, "scene not found"),
+        e => {
+            tracing::warn!("Internal error in scenes: {:?}", e);
+            Status::new(tonic::Code::Internal, "")
+        }
+    }
+}
+
+fn schedule_status(err: scheduler::ScheduleError) -> Status {
+    match err {
+        scheduler::ScheduleError::NotFound => Status::new(tonic::Code::NotFound, "schedule not found"),
+        scheduler::ScheduleError::InvalidTrigger(e) => Status::new(tonic::Code::InvalidArgument, e),
+        e => {
+            tracing::warn!("Internal error in schedules: {:?}", e);
+            Status::new(tonic::Code::Internal, "")
+        }
+    }
+}
+
+fn rule_status(err: rules::RuleError) -> Status {
+    match err {
+        rules::RuleError::NotFound => Status::new(tonic::Code::NotFound, "rule not found"),
+        e => {
+            tracing::warn!("Internal error in rules: {:?}", e);
+            Status::new(tonic::Code::Internal, "")
+        }
+    }
+}
+
+fn device_state(state: i32) -> DeviceState {
+    match home_manager::Status::from_i32(state) {
+        Some(home_manager::Status::On) => DeviceState::On,
+        Some(home_manager::Status::Off) => DeviceState::Off,
+        _ => DeviceState::Unknown,
+    }
+}
+
+/// The arduino command for a typed command, without checking the device kind
+fn arduino_command(command: &home_manager::device_command::Command) -> Result<ArduinoCommand, Status> {
+    use home_manager::device_command::Command;
+    let channel = |value: u32| match value {
+        0..=255 => Ok(value as u8),
+        _ => Err(Status::new(tonic::Code::InvalidArgument, "color channels go from 0 to 255")),
+    };
+    Ok(match command {
+        Command::Set(state) => ArduinoCommand::Set{state: *state},
+        Command::Toggle(_) => ArduinoCommand::Toggle,
+        Command::Query(_) => ArduinoCommand::Query,
+        Command::Read(_) => ArduinoCommand::Read,
+        Command::Brightness(brightness) if brightness.level <= 100 => ArduinoCommand::SetBrightness{level: brightness.level as u8},
+        Command::Brightness(_) => return Err(Status::new(tonic::Code::InvalidArgument, "brightness is a percentage")),
+        Command::Color(color) => ArduinoCommand::SetColor{r: channel(color.r)?, g: channel(color.g)?, b: channel(color.b)?},
+        Command::Run(_) => return Err(Status::new(tonic::Code::InvalidArgument, "arduino devices don't run scripts")),
+    })
+}
+
+/// The typed command for an arduino command, `Check` has none
+fn typed_arduino_command(command: ArduinoCommand) -> Option<home_manager::device_command::Command> {
+    use home_manager::device_command::{Brightness, Color, Command, Query, Read, Toggle};
+    Some(match command {
+        ArduinoCommand::Set{state} => Command::Set(state),
+        ArduinoCommand::Toggle => Command::Toggle(Toggle{}),
+        ArduinoCommand::Query => Command::Query(Query{}),
+        ArduinoCommand::Read => Command::Read(Read{}),
+        ArduinoCommand::SetBrightness{level} => Command::Brightness(Brightness{level: u32::from(level)}),
+        ArduinoCommand::SetColor{r, g, b} => Command::Color(Color{r: u32::from(r), g: u32::from(g), b: u32::from(b)}),
+        ArduinoCommand::Check => return None,
+    })
+}
+
+/// The encoded command to run on a device.
+///
+/// A typed command is checked against the kind and protocol of the device,
+/// the deprecated encoded bytes are only used without one and are checked the same
+fn encode_command(
+    devices: &Devices,
+    actionners: &Actionners,
+    object_id: u32,
+    bytes: &[u8],
+    typed: Option<&home_manager::DeviceCommand>,
+) -> Result<Vec<u8>, Status> {
+    use home_manager::device_command::Command;
+    let obj = match devices.get(object_id) {
+        Ok(Some(obj)) => obj,
+        Ok(None) => return Err(Status::new(tonic::Code::NotFound, format!("device {} not found", object_id))),
+        Err(_) => return Err(Status::new(tonic::Code::Internal, "")),
+    };
+    let protocol = match actionners.protocol(obj.actionner_id) {
+        Some(protocol) => protocol,
+        None => return Err(Status::new(tonic::Code::NotFound, "actionner not found")),
+    };
+    let invalid = |reason| Status::new(tonic::Code::InvalidArgument, reason);
+    let command = match typed.and_then(|t| t.command.as_ref()) {
+        Some(command) => command,
+        None if bytes.is_empty() => return Err(invalid("missing command")),
+        None => {
+            tracing::debug!("Device {} commanded with encoded bytes", object_id);
+            match protocol {
+                Protocol::SSH => {
+                    bincode::deserialize::<SshCommand>(bytes).map_err(|_| invalid("not an ssh command"))?;
+                }
+                Protocol::Arduino => {
+                    let command: ArduinoCommand = bincode::deserialize(bytes).map_err(|_| invalid("not an arduino command"))?;
+                    command.validate(&obj.kind).map_err(invalid)?;
+                }
+            }
+            return Ok(bytes.to_vec())
+        }
+    };
+    let encoded = match (protocol, command) {
+        (Protocol::SSH, Command::Run(run)) => bincode::serialize(&SshCommand::Run{args: run.args.clone()}),
+        (Protocol::SSH, _) => return Err(invalid("ssh devices only run their script")),
+        (Protocol::Arduino, command) => {
+            let command = arduino_command(command)?;
+            command.validate(&obj.kind).map_err(invalid)?;
+            bincode::serialize(&command)
+        }
+    };
+    encoded.map_err(|e| {
+        tracing::warn!("Could not encode command: {:?}", e);
+        Status::new(tonic::Code::Internal, "")
+    })
+}
+
+/// A stored command decoded, when the device and its actionner are known
+fn typed_command(devices: &Devices, actionners: &Actionners, object_id: u32, command: &[u8]) -> Option<home_manager::DeviceCommand> {
+    let protocol = match devices.get(object_id) {
+        Ok(Some(obj)) => actionners.protocol(obj.actionner_id),
+        _ => None,
+    };
+    let command = match protocol? {
+        Protocol::Arduino => bincode::deserialize(command).ok().and_then(typed_arduino_command)?,
+        Protocol::SSH => match bincode::deserialize::<SshCommand>(command).ok()? {
+            SshCommand::Run{args} => home_manager::device_command::Command::Run(home_manager::device_command::Run{args}),
+        },
+    };
+    Some(home_manager::DeviceCommand{command: Some(command)})
+}
+
+fn scene_action(devices: &Devices, actionners: &Actionners, action: scenes::SceneAction) -> home_manager::SceneAction {
+    home_manager::SceneAction{
+        object_id: action.object_id,
+        typed: typed_command(devices, actionners, action.object_id, &action.command),
+        command: action.command,
+    }
+}
+
+fn event_kind(event: home_manager::Event) -> Result<events::EventKind, Status> {
+    let state = device_state(event.state);
+    Ok(match home_manager::EventKind::from_i32(event.kind) {
+        Some(home_manager::EventKind::StateChanged) => events::EventKind::StateChanged{object_id: event.object_id, state},
+        Some(home_manager::EventKind::CommandExecuted) => {
+            events::EventKind::CommandExecuted{object_id: event.object_id, success: event.success, state}
+        }
+        Some(home_manager::EventKind::ActionnerOnline) => events::EventKind::ActionnerOnline{actionner_id: event.actionner_id},
+        Some(home_manager::EventKind::ActionnerOffline) => events::EventKind::ActionnerOffline{actionner_id: event.actionner_id},
+        Some(home_manager::EventKind::DeviceRegistered) => events::EventKind::DeviceRegistered{object_id: event.object_id},
+        Some(home_manager::EventKind::DeviceRemoved) => events::EventKind::DeviceRemoved{object_id: event.object_id},
+        Some(home_manager::EventKind::DeviceUpdated) => events::EventKind::DeviceUpdated{object_id: event.object_id},
+        None => return Err(Status::new(tonic::Code::InvalidArgument, "unknown event kind")),
+    })
+}
+
+fn event_reply(event: &events::Event) -> home_manager::Event {
+    let (kind, state, success) = match event.kind {
+        events::EventKind::StateChanged{state, ..} => (home_manager::EventKind::StateChanged, state, false),
+        events::EventKind::CommandExecuted{success, state, ..} => (home_manager::EventKind::CommandExecuted, state, success),
+        events::EventKind::DeviceRegistered{..} => (home_manager::EventKind::DeviceRegistered, DeviceState::Unknown, false),
+        events::EventKind::DeviceRemoved{..} => (home_manager::EventKind::DeviceRemoved, DeviceState::Unknown, false),
+        events::EventKind::DeviceUpdated{..} => (home_manager::EventKind::DeviceUpdated, DeviceState::Unknown, false),
+        events::EventKind::ActionnerOnline{..} => (home_manager::EventKind::ActionnerOnline, DeviceState::Unknown, false),
+        events::EventKind::ActionnerOffline{..} => (home_manager::EventKind::ActionnerOffline, DeviceState::Unknown, false),
+    };
+    home_manager::Event{
+        kind: kind as i32,
+        object_id: event.object_id().unwrap_or(0),
+        actionner_id: event.actionner_id,
+        state: home_manager::Status::from(state) as i32,
+        success,
+        kind_id: event.kind_id,
+    }
+}
+
+fn watch_matches(filter: &home_manager::WatchEventsRequest, event: &events::Event) -> bool {
+    if !filter.object_ids.is_empty() && !event.object_id().map(|id| filter.object_ids.contains(&id)).unwrap_or(false) {
+        return false
+    }
+    if filter.kind_id != 0 && filter.kind_id != event.kind_id {
+        return false
+    }
+    !filter.by_actionner || filter.actionner_id == event.actionner_id
+}
+
+fn rule_trigger(rule: &home_manager::Rule) -> Result<rules::Trigger, Status> {
+    Ok(match home_manager::EventKind::from_i32(rule.on) {
+        Some(home_manager::EventKind::StateChanged) => rules::Trigger::StateChanged{
+            object_id: rule.object_id,
+            state: match device_state(rule.state) {
+                DeviceState::Unknown => None,
+                state => Some(state),
+            },
+        },
+        Some(home_manager::EventKind::CommandExecuted) => rules::Trigger::CommandExecuted{object_id: rule.object_id},
+        Some(home_manager::EventKind::ActionnerOnline) => rules::Trigger::ActionnerOnline{actionner_id: rule.actionner_id},
+        Some(home_manager::EventKind::ActionnerOffline) => rules::Trigger::ActionnerOffline{actionner_id: rule.actionner_id},
+        Some(home_manager::EventKind::DeviceRegistered)
+        | Some(home_manager::EventKind::DeviceRemoved)
+        | Some(home_manager::EventKind::DeviceUpdated) => {
+            return Err(Status::new(tonic::Code::InvalidArgument, "rules can't fire on registrations, updates or removals"))
+        }
+        None => return Err(Status::new(tonic::Code::InvalidArgument, "unknown event kind")),
+    })
+}
+
+fn rule_reply(devices: &Devices, actionners: &Actionners, name: String, rule: rules::Rule) -> home_manager::Rule {
+    let (on, object_id, actionner_id, state) = match rule.trigger {
+        rules::Trigger::StateChanged{object_id, state} => {
+            (home_manager::EventKind::StateChanged, object_id, 0, state.unwrap_or(DeviceState::Unknown))
+        }
+        rules::Trigger::CommandExecuted{object_id} => (home_manager::EventKind::CommandExecuted, object_id, 0, DeviceState::Unknown),
+        rules::Trigger::ActionnerOnline{actionner_id} => (home_manager::EventKind::ActionnerOnline, 0, actionner_id, DeviceState::Unknown),
+        rules::Trigger::ActionnerOffline{actionner_id} => (home_manager::EventKind::ActionnerOffline, 0, actionner_id, DeviceState::Unknown),
+    };
+    home_manager::Rule{
+        name,
+        on: on as i32,
+        object_id,
+        actionner_id,
+        state: home_manager::Status::from(state) as i32,
+        actions: rule.actions.into_iter().map(|a| scene_action(devices, actionners, a)).collect(),
+        log: rule.log,
+    }
+}
+
+fn auth_status(err: auth::AuthError) -> Status {
+    match err {
+        auth::AuthError::NotFound => Status::new(tonic::Code::NotFound, "token not found"),
+        auth::AuthError::AlreadyExists => Status::new(tonic::Code::AlreadyExists, "token already exists"),
+        e => {
+            tracing::warn!("Internal error in tokens: {:?}", e);
+            Status::new(tonic::Code::Internal, "")
+        }
+    }
+}
+
+fn role(role: i32) -> Result<auth::Role, Status> {
+    match home_manager::Role::from_i32(role) {
+        Some(home_manager::Role::ReadOnly) => Ok(auth::Role::ReadOnly),
+        Some(home_manager::Role::Operator) => Ok(auth::Role::Operator),
+        Some(home_manager::Role::Admin) => Ok(auth::Role::Admin),
+        None => Err(Status::new(tonic::Code::InvalidArgument, "unknown role")),
+    }
+}
+
+impl From<auth::Role> for home_manager::Role {
+    fn from(role: auth::Role) -> Self {
+        match role {
+            auth::Role::ReadOnly => home_manager::Role::ReadOnly,
+            auth::Role::Operator => home_manager::Role::Operator,
+            auth::Role::Admin => home_manager::Role::Admin,
+        }
+    }
+}
+
+fn tls_config(tls: &config::TlsConfig) -> Result<ServerTlsConfig, std::io::Error> {
+    let mut tls_config = ServerTlsConfig::with_rustls();
+    tls_config.identity(Identity::from_pem(std::fs::read(&tls.cert)?, std::fs::read(&tls.key)?));
+    if let Some(client_ca) = &tls.client_ca {
+        tls_config.client_ca_root(Certificate::from_pem(std::fs::read(client_ca)?));
+    }
+    Ok(tls_config)
+}
+
+/// The answer to a call refused before reaching the server
+fn status_response(status: Status) -> http::Response<tonic::body::BoxBody> {
+    http::Response::builder()
+        .header("content-type", "application/grpc")
+        .header("grpc-status", (status.code() as i32).to_string())
+        .header("grpc-message", status.message())
+        .body(tonic::body::BoxBody::empty())
+        .unwrap()
+}
+
+fn reading_status(err: readings::ReadingError) -> Status {
+    tracing::warn!("Internal error in readings: {:?}", err);
+    Status::new(tonic::Code::Internal, "")
+}
+
+fn object_reply(id: u32, obj: Object, state: DeviceState) -> home_manager::Object {
+    home_manager::Object {
+        id,
+        actionner_id: obj.actionner_id,
+        kind: obj.kind.name(),
+        kind_id: obj.kind.id(),
+        name: obj.name,
+        id_in_actionner: obj.id_in_actionner.repr(),
+        state: home_manager::Status::from(state) as i32,
+    }
+}
+
+/// Number of events waiting for the rules before new ones are dropped
+const RULES_QUEUE: usize = 64;
+/// Number of events waiting for each watcher before new ones are dropped
+const WATCH_QUEUE: usize = 64;
+/// Readings removed at once when pruning, and the pause between two batches
+const PRUNE_BATCH: usize = 1000;
+const PRUNE_PAUSE: std::time::Duration = std::time::Duration::from_millis(10);
+/// Most readings returned by `GetReadings`
+const MAX_READINGS: usize = 10_000;
+/// Interval at which a quiet event stream checks that its client is still there
+const WATCH_CHECK: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Clone)]
 pub struct HomeServer {
     devices: Arc<Mutex<Devices>>,
     actionners: Arc<Mutex<Actionners>>,
+    manifest: Option<std::path::PathBuf>,
+    clock: Arc<dyn scheduler::Clock>,
+    events: events::EventBus,
+    tokens: auth::Tokens,
 }
 
 #[derive(Debug)]
@@ -28,6 +384,12 @@ pub enum ServerCreationError {
     Sled(sled::Error),
     Actionners(ActionnerError),
     Devices(DeviceError),
+    Auth(auth::AuthError),
+}
+impl From<auth::AuthError> for ServerCreationError {
+    fn from(err: auth::AuthError) -> ServerCreationError {
+        ServerCreationError::Auth(err)
+    }
 }
 impl From<sled::Error> for ServerCreationError {
     fn from(err: sled::Error) -> ServerCreationError {
@@ -46,21 +408,289 @@ impl From<DeviceError> for ServerCreationError {
 }
 
 impl HomeServer {
-    pub async fn open(data_dir: std::path::PathBuf) -> Result<HomeServer, ServerCreationError> {
-        let actionners = Actionners::open(data_dir.clone()).await?;
+    pub async fn open(config: &Config) -> Result<HomeServer, ServerCreationError> {
+        HomeServer::open_with_clock(config, Arc::new(scheduler::SystemClock)).await
+    }
+    /// Opens the server, running the schedules at the times given by `clock`
+    pub async fn open_with_clock(config: &Config, clock: Arc<dyn scheduler::Clock>) -> Result<HomeServer, ServerCreationError> {
+        let data_dir = config.data_dir();
+        let health_interval = config.health_interval();
+        let tokens = auth::Tokens::open(data_dir.clone())?;
+        let actionners = Actionners::open(data_dir.clone(), config.protocol_settings()).await?;
         let known_actionners = actionners.get_known();
         let devices = Arc::new(Mutex::new(Devices::open(data_dir, &known_actionners)?));
-        Ok(HomeServer {
+        let actionners = Arc::new(Mutex::new(actionners));
+        let events = events::EventBus::default();
+        let monitored = actionners.clone();
+        let health_events = events.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::timer::Interval::new_interval(health_interval);
+            loop {
+                interval.next().await;
+                Actionners::health_check(&monitored, &health_events).await;
+            }
+        });
+        let server = HomeServer {
             devices,
-            actionners: Arc::new(Mutex::new(actionners)),
-        })
+            actionners,
+            manifest: config.manifest.clone(),
+            clock,
+            events,
+            tokens,
+        };
+        tokio::spawn(scheduler::run(server.clone(), server.clock.clone()));
+        let poll_interval = config.readings.poll_interval;
+        let retention = config.readings.retention as i64 * 24 * 3600;
+        let poller = server.clone();
+        let readings = server.devices.lock().await.readings.clone();
+        tokio::spawn(async move {
+            // Readings are still pruned when the sensors are not polled
+            let period = if poll_interval == 0 { 3600 } else { poll_interval };
+            let mut interval = tokio::timer::Interval::new_interval(std::time::Duration::from_secs(period));
+            loop {
+                interval.next().await;
+                if poll_interval != 0 {
+                    poller.poll_sensors().await;
+                }
+                // Pruned in batches without the devices lock, so that commands are not held up
+                let before = poller.clock.now().timestamp() - retention;
+                let mut total = 0;
+                loop {
+                    match readings.prune(before, PRUNE_BATCH) {
+                        Ok(pruned) => {
+                            total += pruned;
+                            if pruned < PRUNE_BATCH {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            tracing::warn!("Could not prune readings: {:?}", e);
+                            break;
+                        }
+                    }
+                    tokio::timer::delay_for(PRUNE_PAUSE).await;
+                }
+                if total > 0 {
+                    tracing::debug!("Pruned {} readings", total);
+                }
+            }
+        });
+        tokio::spawn(rules::run(server.clone(), server.events.subscribe(RULES_QUEUE)));
+        Ok(server)
+    }
+    /// Runs a command on a device and records its new state
+    pub async fn run_command(&self, object_id: u32, command: &[u8]) -> Result<CommandResult, Status> {
+        self.run_command_with_depth(object_id, command, 0, None).await
+    }
+    /// Runs a command sent by `depth` rules fired in a row, sharing `budget`
+    pub async fn run_command_with_depth(&self, object_id: u32, command: &[u8], depth: u32, budget: Option<rules::Budget>) -> Result<CommandResult, Status> {
+        let (obj, state) = {
+            let devices = self.devices.lock().await;
+            match (devices.get(object_id), devices.state(object_id)) {
+                (Ok(Some(obj)), Ok(state)) => (obj, state),
+                (Ok(None), _) => return Err(tonic::Status::new(tonic::Code::NotFound, "device not found")),
+                _ => return Err(tonic::Status::new(tonic::Code::Internal, "")),
+            }
+        };
+        let result = match Actionners::act(&self.actionners, command, &obj, state).await {
+            Ok(Some(result)) => result,
+            Ok(None) => return Err(tonic::Status::new(tonic::Code::NotFound, "actionner not found")),
+            Err(HandlerError::InvalidCommand(_)) => return Err(tonic::Status::new(tonic::Code::InvalidArgument, "invalid command")),
+            Err(HandlerError::Rejected(reason)) => return Err(tonic::Status::new(tonic::Code::InvalidArgument, reason)),
+            Err(HandlerError::Offline) => return Err(tonic::Status::new(tonic::Code::Unavailable, "actionner is offline")),
+            Err(e) => {
+                tracing::warn!("Error in handler: {:?}", e);
+                // The command may have reached the device before the failure
+                self.events.publish(events::Event::device(
+                    events::EventKind::CommandExecuted{object_id, success: false, state},
+                    &obj,
+                    depth,
+                ).with_budget(budget));
+                return Err(tonic::Status::new(tonic::Code::Internal, ""))
+            }
+        };
+        if result.success && result.state != DeviceState::Unknown {
+            if let Err(e) = self.devices.lock().await.set_state(object_id, result.state) {
+                tracing::warn!("Could not save device state: {:?}", e);
+            }
+            if result.state != state {
+                self.events.publish(events::Event::device(
+                    events::EventKind::StateChanged{object_id, state: result.state},
+                    &obj,
+                    depth,
+                ).with_budget(budget.clone()));
+            }
+        }
+        self.events.publish(events::Event::device(
+            events::EventKind::CommandExecuted{object_id, success: result.success, state: result.state},
+            &obj,
+            depth,
+        ).with_budget(budget));
+        Ok(result)
+    }
+    /// Runs commands on several devices, reporting the outcome of each one.
+    ///
+    /// Commands on the same arduino are queued on its connection, everything else runs concurrently
+    pub async fn run_commands<'a>(&self, commands: impl Iterator<Item = (u32, &'a [u8])>) -> Vec<home_manager::DeviceCommandResult> {
+        let results = futures::future::join_all(commands.map(|(object_id, command)| async move {
+            (object_id, self.run_command(object_id, command).await)
+        })).await;
+        results.into_iter().map(|(object_id, result)| match result {
+            Ok(result) => home_manager::DeviceCommandResult{
+                object_id,
+                success: result.success,
+                state: home_manager::Status::from(result.state) as i32,
+                reply: result.reply,
+                error: String::new(),
+            },
+            Err(status) => home_manager::DeviceCommandResult{
+                object_id,
+                success: false,
+                state: home_manager::Status::Unknown as i32,
+                reply: String::new(),
+                error: status.message().to_owned(),
+            },
+        }).collect()
+    }
+    /// The encoded command to run on a device, see `encode_command`
+    pub async fn encode_command(&self, object_id: u32, bytes: &[u8], typed: Option<&home_manager::DeviceCommand>) -> Result<Vec<u8>, Status> {
+        let devices = self.devices.lock().await;
+        let actionners = self.actionners.lock().await;
+        encode_command(&devices, &actionners, object_id, bytes, typed)
+    }
+    /// Reads every sensor and records the values
+    pub async fn poll_sensors(&self) {
+        let sensors: Vec<(u32, Object)> = match self.devices.lock().await.list() {
+            Ok(list) => list.into_iter().filter(|(_, obj)| obj.kind.is_sensor()).collect(),
+            Err(e) => {
+                tracing::warn!("Could not list sensors: {:?}", e);
+                return;
+            }
+        };
+        let readings = futures::future::join_all(sensors.iter().map(|(id, obj)| async move {
+            (*id, Actionners::read(&self.actionners, obj).await)
+        })).await;
+        let time = self.clock.now().timestamp();
+        let devices = self.devices.lock().await;
+        for (id, reading) in readings {
+            match reading {
+                Ok(Some(value)) => {
+                    if let Err(e) = devices.readings.record(id, time, value) {
+                        tracing::warn!("Could not record reading: {:?}", e);
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => tracing::debug!("Could not read sensor {}: {:?}", id, e),
+            }
+        }
+    }
+    /// Applies the home manifest, if one is configured
+    pub async fn apply_manifest(&self) -> Result<manifest::Report, manifest::ManifestError> {
+        let path = match &self.manifest {
+            Some(p) => p,
+            None => return Ok(manifest::Report::default()),
+        };
+        let manifest = manifest::Manifest::load(path)?;
+        manifest.apply(&self.devices, &self.actionners, &self.events).await
     }
 }
 pub struct Devices {
     devices: sled::Db,
+    /// Index of (kind id, device id)
+    by_kind: sled::Tree,
+    /// Index of (actionner id, device id)
+    by_actionner: sled::Tree,
+    /// Last known state of each device, missing means unknown
+    states: sled::Tree,
+    pub groups: groups::Groups,
+    pub scenes: scenes::Scenes,
+    pub rules: rules::Rules,
+    pub readings: readings::Readings,
+    pub schedules: scheduler::Schedules,
     next_id_to_assign: u32,
 }
 
+/// A record to insert, or to remove if `None`, computed ahead of a transaction
+pub type Write = (Vec<u8>, Option<Vec<u8>>);
+
+fn apply_writes(tree: &sled::TransactionalTree, writes: &[Write]) -> Result<(), sled::TransactionError> {
+    for (key, value) in writes {
+        match value {
+            Some(value) => tree.insert(key.clone(), value.clone())?,
+            None => tree.remove(key.clone())?,
+        };
+    }
+    Ok(())
+}
+
+/// Changes written in a single transaction by `Devices::apply`
+#[derive(Default)]
+pub struct DeviceBatch {
+    /// New devices, their ids are given in `Applied::added`
+    pub add: Vec<Object>,
+    /// Devices written with a given id, the flag forgets the state of the device
+    pub put: Vec<(u32, Object, bool)>,
+    /// States written after the devices
+    pub states: Vec<(u32, DeviceState)>,
+    pub remove: Vec<u32>,
+}
+
+/// What `Devices::apply` changed
+pub struct Applied {
+    /// Ids of the created devices, starting with the added ones in order
+    pub added: Vec<u32>,
+    /// Ids of the existing devices that were written
+    pub updated: Vec<u32>,
+    pub removed: Vec<(u32, Object)>,
+}
+
+impl Applied {
+    /// Publishes the registration, update or removal of each device
+    pub fn publish(&self, devices: &Devices, bus: &events::EventBus) -> Result<(), DeviceError> {
+        for &id in &self.added {
+            if let Some(obj) = devices.get(id)? {
+                bus.publish(events::Event::device(events::EventKind::DeviceRegistered{object_id: id}, &obj, 0));
+            }
+        }
+        for &id in &self.updated {
+            if let Some(obj) = devices.get(id)? {
+                bus.publish(events::Event::device(events::EventKind::DeviceUpdated{object_id: id}, &obj, 0));
+            }
+        }
+        for (id, obj) in &self.removed {
+            bus.publish(events::Event::device(events::EventKind::DeviceRemoved{object_id: *id}, obj, 0));
+        }
+        Ok(())
+    }
+}
+
+/// Restricts the devices returned by `Devices::list_filtered`, `None` means no restriction
+#[derive(Default)]
+pub struct DeviceFilter {
+    pub kind_id: Option<u32>,
+    pub actionner_id: Option<u32>,
+    pub group: Option<String>,
+    pub name_contains: Option<String>,
+}
+
+fn index_key(indexed: u32, id: u32) -> [u8; 8] {
+    let mut key = [0; 8];
+    key[..4].copy_from_slice(&indexed.to_be_bytes());
+    key[4..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn index_ids(index: &sled::Tree, indexed: u32) -> Result<Vec<u32>, DeviceError> {
+    let mut ids = Vec::new();
+    for entry in index.scan_prefix(indexed.to_be_bytes()) {
+        let (key, _) = entry?;
+        let mut id = [0; 4];
+        id.copy_from_slice(&key[4..8]);
+        ids.push(u32::from_be_bytes(id));
+    }
+    Ok(ids)
+}
+
 impl Devices {
     pub fn get(&self, id: u32) -> Result<Option<Object>, DeviceError> {
         let data: Object = match self.devices.get(bincode::serialize(&id)?)? {
@@ -69,6 +699,21 @@ impl Devices {
         };
         Ok(Some(data))
     }
+    pub fn state(&self, id: u32) -> Result<DeviceState, DeviceError> {
+        Ok(match self.states.get(bincode::serialize(&id)?)? {
+            Some(s) => bincode::deserialize(&s)?,
+            None => DeviceState::Unknown,
+        })
+    }
+    pub fn set_state(&self, id: u32, state: DeviceState) -> Result<(), DeviceError> {
+        self.states.insert(bincode::serialize(&id)?, bincode::serialize(&state)?)?;
+        Ok(())
+    }
+    fn index(&self, id: u32, obj: &Object) -> Result<(), DeviceError> {
+        self.by_kind.insert(index_key(obj.kind.id(), id), &b""[..])?;
+        self.by_actionner.insert(index_key(obj.actionner_id, id), &b""[..])?;
+        Ok(())
+    }
     pub fn add(&mut self, kind: ObjectKind, actionner_id: u32, name: String, id_in_actionner: ActionnerId) -> Result<u32, DeviceError> {
         let new_obj = Object {
             kind,
@@ -76,12 +721,13 @@ impl Devices {
             id_in_actionner,
             name,
         };
-        let id = self.next_id_to_assign;
-        self.next_id_to_assign += 1;
-        self.devices.insert(bincode::serialize(&id)?, bincode::serialize(&new_obj)?)?;
-        Ok(id)
+        let batch = DeviceBatch {
+            add: vec![new_obj],
+            ..DeviceBatch::default()
+        };
+        Ok(self.apply(batch)?.added[0])
     }
-    pub fn list(&mut self) -> Result<Vec<(u32, Object)>, DeviceError> {
+    pub fn list(&self) -> Result<Vec<(u32, Object)>, DeviceError> {
         let mut list = Vec::with_capacity(self.devices.len());
         for entry in self.devices.iter() {
             let (id, obj) = entry?;
@@ -91,21 +737,196 @@ impl Devices {
         }
         Ok(list)
     }
+    /// Replaces a device keeping its id, the state is forgotten if the device was `rehomed`
+    pub fn update(&mut self, id: u32, obj: Object, rehomed: bool) -> Result<(), DeviceError> {
+        if self.get(id)?.is_none() {
+            return Ok(());
+        }
+        let batch = DeviceBatch {
+            put: vec![(id, obj, rehomed)],
+            ..DeviceBatch::default()
+        };
+        self.apply(batch)?;
+        Ok(())
+    }
+    /// Removes devices along with their index entries and state in a single transaction
+    pub fn remove_all(&mut self, ids: &[u32]) -> Result<Vec<(u32, Object)>, DeviceError> {
+        let batch = DeviceBatch {
+            remove: ids.to_vec(),
+            ..DeviceBatch::default()
+        };
+        Ok(self.apply(batch)?.removed)
+    }
+    pub fn remove(&mut self, id: u32) -> Result<Option<Object>, DeviceError> {
+        Ok(self.remove_all(&[id])?.pop().map(|(_, obj)| obj))
+    }
+    /// Writes a batch of changes in a single transaction, along with the index
+    /// entries and states of the devices. Removed devices are taken out of the
+    /// groups, scenes, schedules and rules in the same transaction
+    pub fn apply(&mut self, batch: DeviceBatch) -> Result<Applied, DeviceError> {
+        let mut next_id = self.next_id_to_assign;
+        let mut added = Vec::with_capacity(batch.add.len());
+        let mut puts = Vec::with_capacity(batch.add.len() + batch.put.len());
+        for obj in batch.add {
+            added.push(next_id);
+            puts.push((next_id, obj, false));
+            next_id += 1;
+        }
+        puts.extend(batch.put);
+        let mut written = Vec::with_capacity(puts.len());
+        let mut updated = Vec::new();
+        for (id, obj, forget_state) in &puts {
+            let old = self.get(*id)?.map(|old| (index_key(old.kind.id(), *id), index_key(old.actionner_id, *id)));
+            match old {
+                Some(_) => updated.push(*id),
+                None if !added.contains(id) => added.push(*id),
+                None => (),
+            }
+            let new = (index_key(obj.kind.id(), *id), index_key(obj.actionner_id, *id));
+            written.push((bincode::serialize(id)?, bincode::serialize(obj)?, old, new, *forget_state));
+            next_id = std::cmp::max(next_id, id + 1);
+        }
+        let mut new_states = Vec::with_capacity(batch.states.len());
+        for (id, state) in &batch.states {
+            new_states.push((bincode::serialize(id)?, bincode::serialize(state)?));
+        }
+        let mut removed = Vec::with_capacity(batch.remove.len());
+        let mut removed_keys = Vec::with_capacity(batch.remove.len());
+        for &id in &batch.remove {
+            if let Some(obj) = self.get(id)? {
+                removed_keys.push((bincode::serialize(&id)?, index_key(obj.kind.id(), id), index_key(obj.actionner_id, id)));
+                removed.push((id, obj));
+            }
+        }
+        let ids: Vec<u32> = removed.iter().map(|(id, _)| *id).collect();
+        let (group_writes, scene_writes, schedule_writes, rule_writes) = if ids.is_empty() {
+            (Vec::new(), Vec::new(), Vec::new(), Vec::new())
+        } else {
+            (self.groups.forget(&ids)?, self.scenes.forget(&ids)?, self.schedules.forget(&ids)?, self.rules.forget(&ids)?)
+        };
+        let unknown = bincode::serialize(&DeviceState::Unknown)?;
+        (
+            &*self.devices,
+            &self.by_kind,
+            &self.by_actionner,
+            &self.states,
+            self.groups.tree(),
+            self.scenes.tree(),
+            self.schedules.tree(),
+            self.rules.tree(),
+        ).transaction(
+            |(devices, by_kind, by_actionner, states, groups, scenes, schedules, rules)| {
+                for (key, value, old, (kind_key, actionner_key), forget_state) in &written {
+                    devices.insert(key.clone(), value.clone())?;
+                    if let Some((old_kind_key, old_actionner_key)) = old {
+                        by_kind.remove(&old_kind_key[..])?;
+                        by_actionner.remove(&old_actionner_key[..])?;
+                    }
+                    by_kind.insert(&kind_key[..], &b""[..])?;
+                    by_actionner.insert(&actionner_key[..], &b""[..])?;
+                    if *forget_state {
+                        states.insert(key.clone(), unknown.clone())?;
+                    }
+                }
+                for (key, state) in &new_states {
+                    states.insert(key.clone(), state.clone())?;
+                }
+                for (key, kind_key, actionner_key) in &removed_keys {
+                    devices.remove(key.clone())?;
+                    states.remove(key.clone())?;
+                    by_kind.remove(&kind_key[..])?;
+                    by_actionner.remove(&actionner_key[..])?;
+                }
+                apply_writes(groups, &group_writes)?;
+                apply_writes(scenes, &scene_writes)?;
+                apply_writes(schedules, &schedule_writes)?;
+                apply_writes(rules, &rule_writes)?;
+                Ok(())
+            },
+        )?;
+        self.next_id_to_assign = next_id;
+        // Readings are only reached through their device, they can go after it
+        if !ids.is_empty() {
+            self.readings.forget(&ids)?;
+        }
+        Ok(Applied { added, updated, removed })
+    }
+    /// Ids of the devices driven by an actionner
+    pub fn driven_by(&self, actionner_id: u32) -> Result<Vec<u32>, DeviceError> {
+        index_ids(&self.by_actionner, actionner_id)
+    }
+    /// Lists devices matching the filter, using the indexes when a kind or an actionner is given
+    pub fn list_filtered(&self, filter: &DeviceFilter) -> Result<Vec<(u32, Object)>, DeviceError> {
+        let mut restrictions: Vec<HashSet<u32>> = Vec::new();
+        if let Some(kind) = filter.kind_id {
+            restrictions.push(index_ids(&self.by_kind, kind)?.into_iter().collect());
+        }
+        if let Some(actionner) = filter.actionner_id {
+            restrictions.push(index_ids(&self.by_actionner, actionner)?.into_iter().collect());
+        }
+        if let Some(group) = &filter.group {
+            restrictions.push(self.groups.members(group)?.into_iter().collect());
+        }
+        let candidates = restrictions.into_iter().fold(None, |acc: Option<HashSet<u32>>, ids| match acc {
+            None => Some(ids),
+            Some(acc) => Some(acc.intersection(&ids).copied().collect()),
+        });
+        let list = match candidates {
+            None => self.list()?,
+            Some(ids) => {
+                let mut list = Vec::with_capacity(ids.len());
+                for id in ids {
+                    if let Some(obj) = self.get(id)? {
+                        list.push((id, obj));
+                    }
+                }
+                list.sort_by_key(|(id, _)| *id);
+                list
+            }
+        };
+        Ok(match &filter.name_contains {
+            None => list,
+            Some(name) => {
+                let name = name.to_lowercase();
+                list.into_iter().filter(|(_, obj)| obj.name.to_lowercase().contains(&name)).collect()
+            }
+        })
+    }
     pub fn open(mut data_dir: std::path::PathBuf, known_actionners: &HashSet<u32>) -> Result<Devices, DeviceError> {
         data_dir.push("devices");
+        let db = sled::Db::open(data_dir)?;
+        migrations::DEVICES.upgrade(&db)?;
         let mut devices = Devices {
-            devices: sled::Db::open(data_dir)?,
+            by_kind: db.open_tree("by_kind")?,
+            by_actionner: db.open_tree("by_actionner")?,
+            states: db.open_tree("states")?,
+            groups: groups::Groups::open(&db)?,
+            scenes: scenes::Scenes::open(&db)?,
+            schedules: scheduler::Schedules::open(&db)?,
+            rules: rules::Rules::open(&db)?,
+            readings: readings::Readings::open(&db)?,
+            devices: db,
             next_id_to_assign: 0,
         };
+        // The indexes are rebuilt from scratch so that they can not drift from the devices
+        devices.by_kind.clear()?;
+        devices.by_actionner.clear()?;
+        let mut orphans = Vec::new();
         for res in devices.devices.iter() {
             let (id, data) = res?;
             let d_id: u32 = bincode::deserialize(&id)?;
             let data: Object = bincode::deserialize(&data)?;
             devices.next_id_to_assign = std::cmp::max(devices.next_id_to_assign, d_id + 1);
-            if !known_actionners.contains(&data.actionner_id) {
-                devices.devices.remove(id)?;
+            if known_actionners.contains(&data.actionner_id) {
+                devices.index(d_id, &data)?;
+            } else {
+                orphans.push(d_id);
             }
         }
+        // Left by an actionner removal that was interrupted
+        for (id, obj) in devices.remove_all(&orphans)? {
+            tracing::warn!("Removed device {} ({}), its actionner {} is gone", id, obj.name, obj.actionner_id);
+        }
         Ok(devices)
     }
 }
@@ -113,6 +934,48 @@ impl Devices {
 pub enum DeviceError {
     Sled(sled::Error),
     Serde(bincode::Error),
+    Transaction(sled::TransactionError),
+    Migration(migrations::MigrationError),
+    Group(groups::GroupError),
+    Scene(scenes::SceneError),
+    Schedule(scheduler::ScheduleError),
+    Rule(rules::RuleError),
+    Reading(readings::ReadingError),
+}
+impl From<readings::ReadingError> for DeviceError {
+    fn from(err: readings::ReadingError) -> Self {
+        Self::Reading(err)
+    }
+}
+impl From<rules::RuleError> for DeviceError {
+    fn from(err: rules::RuleError) -> Self {
+        Self::Rule(err)
+    }
+}
+impl From<scheduler::ScheduleError> for DeviceError {
+    fn from(err: scheduler::ScheduleError) -> Self {
+        Self::Schedule(err)
+    }
+}
+impl From<scenes::SceneError> for DeviceError {
+    fn from(err: scenes::SceneError) -> Self {
+        Self::Scene(err)
+    }
+}
+impl From<groups::GroupError> for DeviceError {
+    fn from(err: groups::GroupError) -> Self {
+        Self::Group(err)
+    }
+}
+impl From<migrations::MigrationError> for DeviceError {
+    fn from(err: migrations::MigrationError) -> Self {
+        Self::Migration(err)
+    }
+}
+impl From<sled::TransactionError> for DeviceError {
+    fn from(err: sled::TransactionError) -> Self {
+        Self::Transaction(err)
+    }
 }
 impl From<sled::Error> for DeviceError {
     fn from(err: sled::Error) -> Self {
@@ -125,28 +988,134 @@ impl From<bincode::Error> for DeviceError {
     }
 }
 
+/// Handlers opened by `Actionners::connect_new`, with their protocol and remote
+#[derive(Default)]
+pub struct Connected(Vec<(Protocol, String, Handler)>);
+
 pub struct Actionners {
     actionner_data: sled::Db,
     actionners: HashMap<u32, Actionner>,
+    settings: Arc<ProtocolSettings>,
     next_id_to_assign: u32,
 }
 
+/// Time after which an actionner not answering a health check is considered offline
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 impl Actionners {
     pub async fn add(&mut self, data: ActionnerData) -> Result<u32, ActionnerError> {
         let id = self.next_id_to_assign;
         self.next_id_to_assign += 1;
         let ser_data = bincode::serialize(&data)?;
         let new_actionner = Actionner {
+            handler: Some(Arc::new(Handler::new(data.protocol, data.remote.clone(), &self.settings).await?)),
             name: data.name,
-            handler: Handler::new(data.protocol, data.remote).await?,
+            protocol: data.protocol,
+            remote: data.remote,
+            health: Health::seen_now(),
         };
         self.actionner_data.insert(bincode::serialize(&id)?, ser_data)?;
         self.actionners.insert(id, new_actionner);
         Ok(id)
     }
-    pub async fn open(mut data_dir: std::path::PathBuf) -> Result<Actionners, ActionnerError> {
+    /// Reserves an id for an actionner written later by `write_all`
+    pub fn reserve_id(&mut self) -> u32 {
+        let id = self.next_id_to_assign;
+        self.next_id_to_assign += 1;
+        id
+    }
+    /// Connects to the actionners not yet registered with the same protocol
+    /// and remote, for a later `write_all`.
+    ///
+    /// The connections are opened concurrently without holding the lock, the
+    /// actionners that can't be reached in time are left out
+    pub async fn connect_new(actionners: &Mutex<Actionners>, wanted: &[ActionnerData]) -> Connected {
+        let (known, settings) = {
+            let actionners = actionners.lock().await;
+            let known: Vec<_> = actionners.actionners.values().map(|act| (act.protocol, act.remote.clone())).collect();
+            (known, actionners.settings.clone())
+        };
+        let new = wanted.iter().filter(|data| !known.iter().any(|(protocol, remote)| *protocol == data.protocol && *remote == data.remote));
+        let connected = futures::future::join_all(new.map(|data| {
+            let settings = &settings;
+            async move {
+                match tokio::timer::Timeout::new(Handler::new(data.protocol, data.remote.clone(), settings), PROBE_TIMEOUT).await {
+                    Ok(Ok(handler)) => Some((data.protocol, data.remote.clone(), handler)),
+                    Ok(Err(e)) => {
+                        tracing::warn!("Actionner {} is offline: {:?}", data.name, e);
+                        None
+                    }
+                    Err(_) => {
+                        tracing::warn!("Actionner {} is offline: connection timed out", data.name);
+                        None
+                    }
+                }
+            }
+        })).await;
+        Connected(connected.into_iter().flatten().collect())
+    }
+    /// Writes or removes (`None`) actionners in a single transaction.
+    ///
+    /// An actionner keeps its handler if its protocol and remote did not
+    /// change, otherwise it takes one from `connected` or is kept offline
+    pub fn write_all(&mut self, changes: Vec<(u32, Option<ActionnerData>)>, connected: &mut Connected) -> Result<(), ActionnerError> {
+        let mut writes: Vec<Write> = Vec::with_capacity(changes.len());
+        for (id, data) in &changes {
+            let value = match data {
+                Some(data) => Some(bincode::serialize(data)?),
+                None => None,
+            };
+            writes.push((bincode::serialize(id)?, value));
+        }
+        (&*self.actionner_data).transaction(|actionner_data| apply_writes(actionner_data, &writes))?;
+        for (id, data) in changes {
+            let data = match data {
+                Some(data) => data,
+                None => {
+                    self.actionners.remove(&id);
+                    continue;
+                }
+            };
+            self.next_id_to_assign = std::cmp::max(self.next_id_to_assign, id + 1);
+            let (handler, health) = match self.actionners.remove(&id) {
+                Some(old) if old.protocol == data.protocol && old.remote == data.remote => (old.handler, old.health),
+                _ => match connected.0.iter().position(|(protocol, remote, _)| *protocol == data.protocol && *remote == data.remote) {
+                    Some(index) => (Some(Arc::new(connected.0.swap_remove(index).2)), Health::seen_now()),
+                    None => (None, Health::default()),
+                },
+            };
+            self.actionners.insert(id, Actionner{
+                handler,
+                name: data.name,
+                protocol: data.protocol,
+                remote: data.remote,
+                health,
+            });
+        }
+        Ok(())
+    }
+    pub fn get_data(&self) -> Vec<(u32, ActionnerData)> {
+        self.actionners.iter().map(|(id, act)| (*id, ActionnerData{
+            protocol: act.protocol,
+            remote: act.remote.clone(),
+            name: act.name.clone(),
+        })).collect()
+    }
+    pub fn find_by_name(&self, name: &str) -> Option<u32> {
+        self.actionners.iter().find(|(_, act)| act.name == name).map(|(id, _)| *id)
+    }
+    pub fn name(&self, id: u32) -> Option<String> {
+        self.actionners.get(&id).map(|act| act.name.clone())
+    }
+    pub fn remove(&mut self, id: u32) -> Result<bool, ActionnerError> {
+        self.actionner_data.remove(bincode::serialize(&id)?)?;
+        Ok(self.actionners.remove(&id).is_some())
+    }
+    /// Opens the registered actionners, the unreachable ones are kept offline
+    pub async fn open(mut data_dir: std::path::PathBuf, settings: ProtocolSettings) -> Result<Actionners, ActionnerError> {
         data_dir.push("actionners");
         let actionner_data = sled::Db::open(data_dir)?;
+        migrations::ACTIONNERS.upgrade(&actionner_data)?;
         let mut actionners = HashMap::with_capacity(actionner_data.len());
         let mut next_id_to_assign = 0;
         for res in actionner_data.iter() {
@@ -154,39 +1123,145 @@ impl Actionners {
             let id: u32 = bincode::deserialize(&id)?;
             let creator: ActionnerData = bincode::deserialize(&creator)?;
             next_id_to_assign = std::cmp::max(next_id_to_assign, id + 1);
-            let handler = match Handler::new(creator.protocol, creator.remote).await {
-                Ok(h) => h,
+            let (handler, health) = match Handler::new(creator.protocol, creator.remote.clone(), &settings).await {
+                Ok(h) => (Some(Arc::new(h)), Health::seen_now()),
                 Err(e) => {
-                    tracing::warn!("Unregistered {} for {:?}", creator.name, e);
-                    actionner_data.remove(bincode::serialize(&id)?)?;
-                    continue
+                    tracing::warn!("Actionner {} is offline: {:?}", creator.name, e);
+                    (None, Health::default())
                 }
             };
-            actionners.insert(id, Actionner{name: creator.name, handler});
+            actionners.insert(id, Actionner{
+                handler,
+                name: creator.name,
+                protocol: creator.protocol,
+                remote: creator.remote,
+                health,
+            });
         }
         Ok(Self {
             actionner_data,
             actionners,
+            settings: Arc::new(settings),
             next_id_to_assign,
         })
     }
+    /// Probes every actionner, recording its health and reconnecting the offline ones.
+    ///
+    /// The probes run without holding the lock. Online actionners are probed
+    /// through their handler, offline ones on a new handler kept if it answers
+    pub async fn health_check(actionners: &Mutex<Actionners>, bus: &events::EventBus) {
+        let (targets, settings) = {
+            let actionners = actionners.lock().await;
+            let targets: Vec<_> = actionners.actionners
+                .iter()
+                .map(|(id, act)| (*id, act.protocol, act.remote.clone(), act.handler.clone()))
+                .collect();
+            (targets, actionners.settings.clone())
+        };
+        // Probed concurrently so that dead actionners don't delay the others
+        let probes = futures::future::join_all(targets.into_iter().map(|(id, protocol, remote, handler)| {
+            let settings = &settings;
+            async move {
+                let start = std::time::Instant::now();
+                let probe = tokio::timer::Timeout::new(async {
+                    match handler {
+                        Some(handler) => handler.check().await.map(|_| None),
+                        None => Handler::new(protocol, remote, settings).await.map(Some),
+                    }
+                }, PROBE_TIMEOUT).await;
+                (id, probe, start.elapsed())
+            }
+        })).await;
+        let mut actionners = actionners.lock().await;
+        for (id, probe, latency) in probes {
+            let act = match actionners.actionners.get_mut(&id) {
+                Some(act) => act,
+                None => continue,
+            };
+            match probe {
+                Ok(Ok(handler)) => {
+                    if let (Some(handler), true) = (handler, act.handler.is_none()) {
+                        tracing::info!("Actionner {} is back online", act.name);
+                        act.handler = Some(Arc::new(handler));
+                        bus.publish(events::Event::actionner(events::EventKind::ActionnerOnline{actionner_id: id}, id));
+                    }
+                    act.health.last_seen = Some(std::time::SystemTime::now());
+                    act.health.latency = Some(latency);
+                }
+                Ok(Err(e)) => {
+                    if act.handler.take().is_some() {
+                        tracing::warn!("Actionner {} went offline: {:?}", act.name, e);
+                        bus.publish(events::Event::actionner(events::EventKind::ActionnerOffline{actionner_id: id}, id));
+                    }
+                    act.health.latency = None;
+                }
+                Err(_) => {
+                    if act.handler.take().is_some() {
+                        tracing::warn!("Actionner {} went offline: probe timed out", act.name);
+                        bus.publish(events::Event::actionner(events::EventKind::ActionnerOffline{actionner_id: id}, id));
+                    }
+                    act.health.latency = None;
+                }
+            }
+        }
+    }
     pub fn get_list(&self) -> impl Iterator<Item = home_manager::Actionner> + '_ {
-        self.actionners.iter().map(|(id, act)| home_manager::Actionner{id: *id, name: act.name.clone(), protocol: act.handler.protocol().name()})
+        self.actionners.iter().map(|(id, act)| home_manager::Actionner{
+            id: *id,
+            name: act.name.clone(),
+            protocol: act.protocol.name(),
+            online: act.handler.is_some(),
+            last_seen: act.health.last_seen
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            latency_ms: act.health.latency.map(|l| l.as_millis() as u32).unwrap_or(0),
+        })
     }
     pub fn get_known(&self) -> HashSet<u32> {
-        self.get_list().map(|a| a.id).collect()
+        self.actionners.keys().copied().collect()
     }
     pub fn protocol(&self, id: u32) -> Option<Protocol> {
-        self.actionners.get(&id).map(|e| e.handler.protocol())
+        self.actionners.get(&id).map(|e| e.protocol)
     }
-    pub async fn act(&mut self, command: &[u8], object: &Object) -> Result<Option<CommandResult>, HandlerError> {
-        match self.actionners.get_mut(&object.actionner_id) {
-            Some(hdlr) => Ok(Some(hdlr.handler.command(command, object).await?)),
+    fn handler(&self, id: u32) -> Result<Option<Arc<Handler>>, HandlerError> {
+        match self.actionners.get(&id) {
+            Some(Actionner{handler: Some(handler), ..}) => Ok(Some(handler.clone())),
+            Some(Actionner{handler: None, ..}) => Err(HandlerError::Offline),
             None => Ok(None),
         }
     }
+    /// Runs a command on an object.
+    ///
+    /// The actionners are only locked to find the handler, so that commands can
+    /// run concurrently
+    pub async fn act(actionners: &Mutex<Actionners>, command: &[u8], object: &Object, state: DeviceState) -> Result<Option<CommandResult>, HandlerError> {
+        let handler = match actionners.lock().await.handler(object.actionner_id)? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        let result = handler.command(command, object, state).await?;
+        Ok(Some(result))
+    }
+    pub async fn query(actionners: &Mutex<Actionners>, object: &Object) -> Result<Option<DeviceState>, HandlerError> {
+        let handler = match actionners.lock().await.handler(object.actionner_id)? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        let state = handler.query(object).await?;
+        Ok(Some(state))
+    }
+    /// Reads the current value of a sensor
+    pub async fn read(actionners: &Mutex<Actionners>, object: &Object) -> Result<Option<f64>, HandlerError> {
+        let handler = match actionners.lock().await.handler(object.actionner_id)? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        let value = handler.read(object).await?;
+        Ok(Some(value))
+    }
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ActionnerData {
     protocol: Protocol,
     remote: String,
@@ -197,6 +1272,18 @@ pub enum ActionnerError {
     SerDeError(bincode::Error),
     Handler(HandlerError),
     Database(sled::Error),
+    Migration(migrations::MigrationError),
+    Transaction(sled::TransactionError),
+}
+impl From<sled::TransactionError> for ActionnerError {
+    fn from(err: sled::TransactionError) -> Self {
+        Self::Transaction(err)
+    }
+}
+impl From<migrations::MigrationError> for ActionnerError {
+    fn from(err: migrations::MigrationError) -> Self {
+        Self::Migration(err)
+    }
 }
 impl From<HandlerError> for ActionnerError {
     fn from(err: HandlerError) -> Self {
@@ -214,28 +1301,58 @@ impl From<bincode::Error> for ActionnerError {
     }
 }
 pub struct Actionner {
-    handler: Handler,
+    /// `None` while the actionner is offline
+    handler: Option<Arc<Handler>>,
     name: String,
+    protocol: Protocol,
+    remote: String,
+    health: Health,
 }
-
-#[tonic::async_trait]
-impl HomeManager for HomeServer {
+/// Result of the health checks of an actionner
+#[derive(Default)]
+pub struct Health {
+    last_seen: Option<std::time::SystemTime>,
+    /// Duration of the last successful check
+    latency: Option<std::time::Duration>,
+}
+impl Health {
+    fn seen_now() -> Health {
+        Health {
+            last_seen: Some(std::time::SystemTime::now()),
+            latency: None,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl HomeManager for HomeServer {
     async fn list_device(
         &self,
-        _request: Request<ListDeviceRequest>,
+        request: Request<ListDeviceRequest>,
     ) -> Result<Response<ListDeviceReply>, Status> {
         tracing::info!("Listing devices");
-        let list = match self.devices.lock().await.list() {
+        let request = request.into_inner();
+        let filter = DeviceFilter {
+            kind_id: Some(request.kind_id).filter(|&k| k != 0),
+            actionner_id: Some(request.actionner_id).filter(|_| request.by_actionner),
+            group: Some(request.group).filter(|g| !g.is_empty()),
+            name_contains: Some(request.name_contains).filter(|n| !n.is_empty()),
+        };
+        let devices = self.devices.lock().await;
+        let list = match devices.list_filtered(&filter) {
             Ok(i) => i,
+            Err(DeviceError::Group(groups::GroupError::NotFound)) => return Err(Status::new(tonic::Code::NotFound, "group not found")),
             Err(_) => return Err(Status::new(tonic::Code::Internal, "internal error")),
         };
-        let reply = ListDeviceReply {
-            objects: list
-                .into_iter()
-                .map(|(id,obj)| home_manager::Object{id, actionner_id: obj.actionner_id, kind: obj.kind.name(), kind_id: obj.kind.id(), name: obj.name, id_in_actionner: obj.id_in_actionner.repr()})
-                .collect(),
-        };
-        Ok(Response::new(reply))
+        let mut objects = Vec::with_capacity(list.len());
+        for (id, obj) in list {
+            let state = match devices.state(id) {
+                Ok(s) => s,
+                Err(_) => return Err(Status::new(tonic::Code::Internal, "internal error")),
+            };
+            objects.push(object_reply(id, obj, state));
+        }
+        Ok(Response::new(ListDeviceReply{objects}))
     }
     async fn register_device(&self, request: Request<home_manager::RegisterDeviceRequest>)
         -> Result<Response<home_manager::RegisterDeviceReply>, Status> {
@@ -246,28 +1363,125 @@ impl HomeManager for HomeServer {
                 return Err(Status::new(tonic::Code::InvalidArgument, "invalid category"))
             }
         };
-        let protocol = match self.actionners.lock().await.protocol(request.actionner_id) {
+        // Both locks are held until the device is added, so that its actionner can't be removed meanwhile
+        let mut devices = self.devices.lock().await;
+        let actionners = self.actionners.lock().await;
+        let protocol = match actionners.protocol(request.actionner_id) {
             Some(p) => p,
             None => return Err(Status::new(tonic::Code::NotFound, "actionner not found")),
         };
-        let id = match protocol {
-            Protocol::Arduino => ActionnerId::Arduino(match request.id_in_actionner.parse() {
-                Ok(i) => i,
-                Err(_) => return Err(Status::new(tonic::Code::InvalidArgument, "invalid id for protocol"))
-            }),
-            _ => unimplemented!(),
+        if !protocol.drives(&kind) {
+            return Err(Status::new(tonic::Code::InvalidArgument, "the actionner can't drive this category"))
+        }
+        let id = match protocol.parse_id(&request.id_in_actionner) {
+            Ok(id) => id,
+            Err(_) => return Err(Status::new(tonic::Code::InvalidArgument, "invalid id for protocol")),
         };
-        match self.devices.lock().await.add(kind, request.actionner_id, request.name, id) {
+        let kind_id = kind.id();
+        let added = devices.add(kind, request.actionner_id, request.name, id);
+        drop(actionners);
+        drop(devices);
+        match added {
             Err(e) => {
                 tracing::warn!("Internal error adding device: {:?}", e);
                 Err(Status::new(tonic::Code::Internal, ""))
             }
             Ok(id) => {
+                self.events.publish(events::Event{
+                    kind: events::EventKind::DeviceRegistered{object_id: id},
+                    kind_id,
+                    actionner_id: request.actionner_id,
+                    depth: 0,
+                    budget: None,
+                });
                 Ok(Response::new(home_manager::RegisterDeviceReply{id}))
             }
         }
     }
 
+    async fn update_device(
+        &self,
+        request: Request<home_manager::UpdateDeviceRequest>
+    ) -> Result<Response<home_manager::UpdateDeviceReply>, Status> {
+        let request = request.into_inner();
+        let mut devices = self.devices.lock().await;
+        let mut obj = match devices.get(request.id) {
+            Ok(Some(obj)) => obj,
+            Ok(None) => return Err(Status::new(tonic::Code::NotFound, "device not found")),
+            Err(_) => return Err(Status::new(tonic::Code::Internal, "")),
+        };
+        if !request.name.is_empty() {
+            obj.name = request.name;
+        }
+        if !request.kind.is_empty() {
+            obj.kind = match request.kind.parse() {
+                Ok(k) => k,
+                Err(_) => return Err(Status::new(tonic::Code::InvalidArgument, "invalid category")),
+            };
+        }
+        let rehomed = request.change_actionner || !request.id_in_actionner.is_empty();
+        if rehomed {
+            if request.change_actionner {
+                obj.actionner_id = request.actionner_id;
+            }
+            let protocol = match self.actionners.lock().await.protocol(obj.actionner_id) {
+                Some(p) => p,
+                None => return Err(Status::new(tonic::Code::NotFound, "actionner not found")),
+            };
+            let id_in_actionner = if request.id_in_actionner.is_empty() {
+                obj.id_in_actionner.repr()
+            } else {
+                request.id_in_actionner
+            };
+            obj.id_in_actionner = match protocol.parse_id(&id_in_actionner) {
+                Ok(id) => id,
+                Err(_) => return Err(Status::new(tonic::Code::InvalidArgument, "invalid id for protocol")),
+            };
+        }
+        if rehomed || !request.kind.is_empty() {
+            match self.actionners.lock().await.protocol(obj.actionner_id) {
+                Some(protocol) if !protocol.drives(&obj.kind) => {
+                    return Err(Status::new(tonic::Code::InvalidArgument, "the actionner can't drive this category"))
+                }
+                _ => (),
+            }
+        }
+        let updated = events::Event::device(events::EventKind::DeviceUpdated{object_id: request.id}, &obj, 0);
+        let forgotten = events::Event::device(events::EventKind::StateChanged{object_id: request.id, state: DeviceState::Unknown}, &obj, 0);
+        let state = devices.state(request.id).unwrap_or(DeviceState::Unknown);
+        match devices.update(request.id, obj, rehomed) {
+            Ok(()) => {
+                self.events.publish(updated);
+                if rehomed && state != DeviceState::Unknown {
+                    self.events.publish(forgotten);
+                }
+                Ok(Response::new(home_manager::UpdateDeviceReply{}))
+            }
+            Err(e) => {
+                tracing::warn!("Internal error updating device: {:?}", e);
+                Err(Status::new(tonic::Code::Internal, ""))
+            }
+        }
+    }
+
+    async fn remove_device(
+        &self,
+        request: Request<home_manager::RemoveDeviceRequest>
+    ) -> Result<Response<home_manager::RemoveDeviceReply>, Status> {
+        let request = request.into_inner();
+        match self.devices.lock().await.remove(request.id) {
+            Ok(Some(obj)) => {
+                self.events.publish(events::Event::device(events::EventKind::DeviceRemoved{object_id: request.id}, &obj, 0));
+                Ok(Response::new(home_manager::RemoveDeviceReply{}))
+            }
+            Ok(None) => Err(Status::new(tonic::Code::NotFound, "device not found")),
+            Err(e) => {
+                tracing::warn!("Internal error removing device: {:?}", e);
+                Err(Status::new(tonic::Code::Internal, ""))
+            }
+        }
+    }
+
     async fn list_actionner(
         &self,
         _request: Request<home_manager::ListActionnerRequest>
@@ -314,42 +1528,704 @@ impl HomeManager for HomeServer {
         }
     }
 
+    async fn remove_actionner(
+        &self,
+        request: Request<home_manager::RemoveActionnerRequest>
+    ) -> Result<Response<home_manager::RemoveActionnerReply>, Status> {
+        let request = request.into_inner();
+        // Both locks are held so that no device can be registered on the actionner meanwhile
+        let mut devices = self.devices.lock().await;
+        let mut actionners = self.actionners.lock().await;
+        if actionners.protocol(request.id).is_none() {
+            return Err(Status::new(tonic::Code::NotFound, "actionner not found"))
+        }
+        let driven = match devices.driven_by(request.id) {
+            Ok(ids) => ids,
+            Err(e) => {
+                tracing::warn!("Internal error listing devices: {:?}", e);
+                return Err(Status::new(tonic::Code::Internal, ""))
+            }
+        };
+        if !driven.is_empty() && !request.cascade {
+            return Err(Status::new(tonic::Code::FailedPrecondition, "actionner still drives devices"))
+        }
+        // The actionner goes first: devices left without actionner by a crash in
+        // between are removed when the devices are opened again
+        if let Err(e) = actionners.remove(request.id) {
+            tracing::warn!("Internal error removing actionner: {:?}", e);
+            return Err(Status::new(tonic::Code::Internal, ""))
+        }
+        let removed = match devices.remove_all(&driven) {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::warn!("Internal error removing devices: {:?}", e);
+                return Err(Status::new(tonic::Code::Internal, ""))
+            }
+        };
+        for (id, obj) in &removed {
+            self.events.publish(events::Event::device(events::EventKind::DeviceRemoved{object_id: *id}, obj, 0));
+        }
+        let removed_devices = removed.into_iter().map(|(id, _)| id).collect();
+        Ok(Response::new(home_manager::RemoveActionnerReply{removed_devices}))
+    }
+
+    async fn reload(
+        &self,
+        _request: Request<home_manager::ReloadRequest>
+    ) -> Result<Response<home_manager::ReloadReply>, Status> {
+        if self.manifest.is_none() {
+            return Err(Status::new(tonic::Code::FailedPrecondition, "no manifest configured"))
+        }
+        match self.apply_manifest().await {
+            Ok(report) => Ok(Response::new(home_manager::ReloadReply{
+                created: report.created,
+                updated: report.updated,
+                removed: report.removed,
+            })),
+            Err(e @ manifest::ManifestError::Io(_))
+            | Err(e @ manifest::ManifestError::Toml(_))
+            | Err(e @ manifest::ManifestError::Invalid(_)) => {
+                Err(Status::new(tonic::Code::InvalidArgument, e.to_string()))
+            }
+            Err(e) => {
+                tracing::warn!("Error applying manifest: {:?}", e);
+                Err(Status::new(tonic::Code::Internal, ""))
+            }
+        }
+    }
+
+    async fn export_state(
+        &self,
+        _request: Request<home_manager::ExportStateRequest>
+    ) -> Result<Response<home_manager::ExportStateReply>, Status> {
+        let devices = self.devices.lock().await;
+        let actionners = self.actionners.lock().await;
+        let document = match backup::export(&devices, &actionners).and_then(|d| Ok(serde_json::to_string_pretty(&d)?)) {
+            Ok(d) => d,
+            Err(e) => {
+                tracing::warn!("Error exporting state: {:?}", e);
+                return Err(Status::new(tonic::Code::Internal, ""))
+            }
+        };
+        Ok(Response::new(home_manager::ExportStateReply{document}))
+    }
+
+    async fn import_state(
+        &self,
+        request: Request<home_manager::ImportStateRequest>
+    ) -> Result<Response<home_manager::ImportStateReply>, Status> {
+        let request = request.into_inner();
+        let document = match backup::Document::parse(&request.document) {
+            Ok(d) => d,
+            Err(backup::BackupError::UnsupportedVersion(v)) => {
+                return Err(Status::new(tonic::Code::InvalidArgument, format!("unsupported version {}", v)))
+            }
+            Err(e) => return Err(Status::new(tonic::Code::InvalidArgument, format!("invalid document: {:?}", e))),
+        };
+        let mut connected = if request.dry_run {
+            Connected::default()
+        } else {
+            Actionners::connect_new(&self.actionners, &document.actionner_data()).await
+        };
+        let mut devices = self.devices.lock().await;
+        let mut actionners = self.actionners.lock().await;
+        let problems = match document.check(&devices, &actionners, request.overwrite) {
+            Ok(p) => p,
+            Err(e) => {
+                tracing::warn!("Error checking import: {:?}", e);
+                return Err(Status::new(tonic::Code::Internal, ""))
+            }
+        };
+        if request.dry_run || !problems.is_empty() {
+            return Ok(Response::new(home_manager::ImportStateReply{problems, applied: false}))
+        }
+        match document.import(&mut devices, &mut actionners, &mut connected, &self.events) {
+            Ok(()) => Ok(Response::new(home_manager::ImportStateReply{problems, applied: true})),
+            Err(e) => {
+                tracing::warn!("Error importing state: {:?}", e);
+                Err(Status::new(tonic::Code::Internal, ""))
+            }
+        }
+    }
+
+    async fn list_protocol(
+        &self,
+        _request: Request<home_manager::ListProtocolRequest>
+    ) -> Result<Response<home_manager::ListProtocolReply>, Status> {
+        Ok(Response::new(home_manager::ListProtocolReply{
+            protocols: Protocol::all()
+                .iter()
+                .map(|p| home_manager::Protocol{name: p.name(), desc: p.desc(), commands: p.commands()})
+                .collect(),
+        }))
+    }
+
     async fn command(&self, request: Request<home_manager::CommandRequest>) -> Result<Response<home_manager::CommandReply>, Status> {
         let request = request.into_inner();
-        match self.devices.lock().await.get(request.object_id) {
-            Err(_) => return Err(tonic::Status::new(tonic::Code::Internal, "")),
-            Ok(None) => return Err(tonic::Status::new(tonic::Code::NotFound, "device not found")),
-            Ok(Some(obj)) => match self.actionners.lock().await.act(&request.command, &obj).await {
-                Ok(_) => (),  // One day
-                Err(e) => {
-                    tracing::warn!("Error in handler: {:?}", e);
-                    return Err(tonic::Status::new(tonic::Code::Internal, ""))
+        let command = self.encode_command(request.object_id, &request.command, request.typed.as_ref()).await?;
+        let result = self.run_command(request.object_id, &command).await?;
+        Ok(Response::new(home_manager::CommandReply{
+            success: result.success,
+            state: home_manager::Status::from(result.state) as i32,
+            reply: result.reply,
+        }))
+    }
+
+    async fn create_group(
+        &self,
+        request: Request<home_manager::CreateGroupRequest>
+    ) -> Result<Response<home_manager::CreateGroupReply>, Status> {
+        let request = request.into_inner();
+        if request.name.is_empty() {
+            return Err(Status::new(tonic::Code::InvalidArgument, "empty group name"))
+        }
+        self.devices.lock().await.groups.create(&request.name).map_err(group_status)?;
+        Ok(Response::new(home_manager::CreateGroupReply{}))
+    }
+
+    async fn delete_group(
+        &self,
+        request: Request<home_manager::DeleteGroupRequest>
+    ) -> Result<Response<home_manager::DeleteGroupReply>, Status> {
+        let request = request.into_inner();
+        self.devices.lock().await.groups.delete(&request.name).map_err(group_status)?;
+        Ok(Response::new(home_manager::DeleteGroupReply{}))
+    }
+
+    async fn add_to_group(
+        &self,
+        request: Request<home_manager::AddToGroupRequest>
+    ) -> Result<Response<home_manager::AddToGroupReply>, Status> {
+        let request = request.into_inner();
+        let devices = self.devices.lock().await;
+        match devices.get(request.object_id) {
+            Ok(Some(_)) => (),
+            Ok(None) => return Err(Status::new(tonic::Code::NotFound, "device not found")),
+            Err(_) => return Err(Status::new(tonic::Code::Internal, "")),
+        }
+        devices.groups.add(&request.group, request.object_id).map_err(group_status)?;
+        Ok(Response::new(home_manager::AddToGroupReply{}))
+    }
+
+    async fn remove_from_group(
+        &self,
+        request: Request<home_manager::RemoveFromGroupRequest>
+    ) -> Result<Response<home_manager::RemoveFromGroupReply>, Status> {
+        let request = request.into_inner();
+        self.devices.lock().await.groups.remove(&request.group, request.object_id).map_err(group_status)?;
+        Ok(Response::new(home_manager::RemoveFromGroupReply{}))
+    }
+
+    async fn list_groups(
+        &self,
+        _request: Request<home_manager::ListGroupsRequest>
+    ) -> Result<Response<home_manager::ListGroupsReply>, Status> {
+        let groups = self.devices.lock().await.groups.list().map_err(group_status)?;
+        Ok(Response::new(home_manager::ListGroupsReply{
+            groups: groups
+                .into_iter()
+                .map(|(name, members)| home_manager::Group{name, object_ids: members.into_iter().collect()})
+                .collect(),
+        }))
+    }
+
+    async fn group_command(
+        &self,
+        request: Request<home_manager::GroupCommandRequest>
+    ) -> Result<Response<home_manager::GroupCommandReply>, Status> {
+        let request = request.into_inner();
+        let members = self.devices.lock().await.groups.members(&request.group).map_err(group_status)?;
+        // A typed command may not apply to every member, those are reported as failed
+        let mut commands = Vec::with_capacity(members.len());
+        let mut refused = Vec::new();
+        for object_id in members {
+            match self.encode_command(object_id, &request.command, request.typed.as_ref()).await {
+                Ok(command) => commands.push((object_id, command)),
+                Err(status) => refused.push(home_manager::DeviceCommandResult{
+                    object_id,
+                    success: false,
+                    state: home_manager::Status::Unknown as i32,
+                    reply: String::new(),
+                    error: status.message().to_owned(),
+                }),
+            }
+        }
+        let mut results = self.run_commands(commands.iter().map(|(object_id, command)| (*object_id, &command[..]))).await;
+        results.extend(refused);
+        Ok(Response::new(home_manager::GroupCommandReply{results}))
+    }
+
+    async fn create_scene(
+        &self,
+        request: Request<home_manager::CreateSceneRequest>
+    ) -> Result<Response<home_manager::CreateSceneReply>, Status> {
+        let request = request.into_inner();
+        if request.name.is_empty() {
+            return Err(Status::new(tonic::Code::InvalidArgument, "empty scene name"))
+        }
+        // Checked and stored under the same lock so that the devices can't be removed in between
+        let devices = self.devices.lock().await;
+        let actionners = self.actionners.lock().await;
+        let mut actions = Vec::with_capacity(request.actions.len());
+        for action in request.actions {
+            let command = encode_command(&devices, &actionners, action.object_id, &action.command, action.typed.as_ref())?;
+            actions.push(scenes::SceneAction{object_id: action.object_id, command});
+        }
+        drop(actionners);
+        devices.scenes.set(&request.name, &actions).map_err(scene_status)?;
+        Ok(Response::new(home_manager::CreateSceneReply{}))
+    }
+
+    async fn delete_scene(
+        &self,
+        request: Request<home_manager::DeleteSceneRequest>
+    ) -> Result<Response<home_manager::DeleteSceneReply>, Status> {
+        let request = request.into_inner();
+        self.devices.lock().await.scenes.delete(&request.name).map_err(scene_status)?;
+        Ok(Response::new(home_manager::DeleteSceneReply{}))
+    }
+
+    async fn list_scenes(
+        &self,
+        _request: Request<home_manager::ListScenesRequest>
+    ) -> Result<Response<home_manager::ListScenesReply>, Status> {
+        let devices = self.devices.lock().await;
+        let scenes = devices.scenes.list().map_err(scene_status)?;
+        let actionners = self.actionners.lock().await;
+        Ok(Response::new(home_manager::ListScenesReply{
+            scenes: scenes
+                .into_iter()
+                .map(|(name, actions)| home_manager::Scene{
+                    name,
+                    actions: actions.into_iter().map(|a| scene_action(&devices, &actionners, a)).collect(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn activate_scene(
+        &self,
+        request: Request<home_manager::ActivateSceneRequest>
+    ) -> Result<Response<home_manager::ActivateSceneReply>, Status> {
+        let request = request.into_inner();
+        let actions = self.devices.lock().await.scenes.get(&request.name).map_err(scene_status)?;
+        Ok(Response::new(home_manager::ActivateSceneReply{
+            results: self.run_commands(actions.iter().map(|a| (a.object_id, &a.command[..]))).await,
+        }))
+    }
+
+    async fn create_schedule(
+        &self,
+        request: Request<home_manager::CreateScheduleRequest>
+    ) -> Result<Response<home_manager::CreateScheduleReply>, Status> {
+        let request = request.into_inner();
+        let trigger = match (request.cron.is_empty(), request.interval_seconds) {
+            (false, 0) => scheduler::Trigger::Cron{expression: request.cron},
+            (true, seconds) if seconds != 0 => scheduler::Trigger::Interval{seconds},
+            _ => return Err(Status::new(tonic::Code::InvalidArgument, "set one of cron or interval_seconds")),
+        };
+        let devices = self.devices.lock().await;
+        let command = encode_command(&devices, &*self.actionners.lock().await, request.object_id, &request.command, request.typed.as_ref())?;
+        let now = self.clock.now();
+        let (id, next_run) = devices.schedules.add(request.object_id, command, trigger, now).map_err(schedule_status)?;
+        Ok(Response::new(home_manager::CreateScheduleReply{id, next_run}))
+    }
+
+    async fn delete_schedule(
+        &self,
+        request: Request<home_manager::DeleteScheduleRequest>
+    ) -> Result<Response<home_manager::DeleteScheduleReply>, Status> {
+        let request = request.into_inner();
+        self.devices.lock().await.schedules.remove(request.id).map_err(schedule_status)?;
+        Ok(Response::new(home_manager::DeleteScheduleReply{}))
+    }
+
+    async fn list_schedules(
+        &self,
+        _request: Request<home_manager::ListSchedulesRequest>
+    ) -> Result<Response<home_manager::ListSchedulesReply>, Status> {
+        let devices = self.devices.lock().await;
+        let schedules = devices.schedules.list().map_err(schedule_status)?;
+        let actionners = self.actionners.lock().await;
+        Ok(Response::new(home_manager::ListSchedulesReply{
+            schedules: schedules
+                .into_iter()
+                .map(|(id, schedule)| {
+                    let (cron, interval_seconds) = match schedule.trigger {
+                        scheduler::Trigger::Cron{expression} => (expression, 0),
+                        scheduler::Trigger::Interval{seconds} => (String::new(), seconds),
+                    };
+                    home_manager::Schedule{
+                        id,
+                        object_id: schedule.object_id,
+                        typed: typed_command(&devices, &actionners, schedule.object_id, &schedule.command),
+                        command: schedule.command,
+                        cron,
+                        interval_seconds,
+                        next_run: if schedule.next_run == scheduler::DISABLED { 0 } else { schedule.next_run },
+                    }
+                })
+                .collect(),
+        }))
+    }
+
+    async fn create_rule(
+        &self,
+        request: Request<home_manager::CreateRuleRequest>
+    ) -> Result<Response<home_manager::CreateRuleReply>, Status> {
+        let rule = match request.into_inner().rule {
+            Some(rule) => rule,
+            None => return Err(Status::new(tonic::Code::InvalidArgument, "missing rule")),
+        };
+        if rule.name.is_empty() {
+            return Err(Status::new(tonic::Code::InvalidArgument, "empty rule name"))
+        }
+        let trigger = rule_trigger(&rule)?;
+        // Checked and stored under the same lock so that the devices can't be removed in between
+        let devices = self.devices.lock().await;
+        let actionners = self.actionners.lock().await;
+        let mut actions = Vec::with_capacity(rule.actions.len());
+        for action in rule.actions {
+            let command = encode_command(&devices, &actionners, action.object_id, &action.command, action.typed.as_ref())?;
+            actions.push(scenes::SceneAction{object_id: action.object_id, command});
+        }
+        drop(actionners);
+        devices.rules.set(&rule.name, &rules::Rule{trigger, actions, log: rule.log}).map_err(rule_status)?;
+        Ok(Response::new(home_manager::CreateRuleReply{}))
+    }
+
+    async fn delete_rule(
+        &self,
+        request: Request<home_manager::DeleteRuleRequest>
+    ) -> Result<Response<home_manager::DeleteRuleReply>, Status> {
+        let request = request.into_inner();
+        self.devices.lock().await.rules.delete(&request.name).map_err(rule_status)?;
+        Ok(Response::new(home_manager::DeleteRuleReply{}))
+    }
+
+    async fn list_rules(
+        &self,
+        _request: Request<home_manager::ListRulesRequest>
+    ) -> Result<Response<home_manager::ListRulesReply>, Status> {
+        let devices = self.devices.lock().await;
+        let rules = devices.rules.list().map_err(rule_status)?;
+        let actionners = self.actionners.lock().await;
+        Ok(Response::new(home_manager::ListRulesReply{
+            rules: rules.into_iter().map(|(name, rule)| rule_reply(&devices, &actionners, name, rule)).collect(),
+        }))
+    }
+
+    async fn evaluate_rules(
+        &self,
+        request: Request<home_manager::EvaluateRulesRequest>
+    ) -> Result<Response<home_manager::EvaluateRulesReply>, Status> {
+        let event = match request.into_inner().event {
+            Some(event) => event_kind(event)?,
+            None => return Err(Status::new(tonic::Code::InvalidArgument, "missing event")),
+        };
+        let devices = self.devices.lock().await;
+        let rules = devices.rules.matching(&event).map_err(rule_status)?;
+        let actionners = self.actionners.lock().await;
+        Ok(Response::new(home_manager::EvaluateRulesReply{
+            rules: rules.into_iter().map(|(name, rule)| rule_reply(&devices, &actionners, name, rule)).collect(),
+        }))
+    }
+
+    type WatchEventsStream = mpsc::Receiver<Result<home_manager::Event, Status>>;
+
+    async fn watch_events(
+        &self,
+        request: Request<home_manager::WatchEventsRequest>
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let filter = request.into_inner();
+        let mut events = self.events.subscribe(WATCH_QUEUE);
+        let (mut sender, receiver) = mpsc::channel(WATCH_QUEUE);
+        tokio::spawn(async move {
+            loop {
+                let event = match tokio::timer::Timeout::new(events.recv(), WATCH_CHECK).await {
+                    Ok(Some(event)) => event,
+                    Ok(None) => break,
+                    Err(_) => {
+                        // Nothing was sent for a while, the client may have left
+                        let ready = futures::future::poll_fn(|cx| std::task::Poll::Ready(sender.poll_ready(cx))).await;
+                        if let std::task::Poll::Ready(Err(_)) = ready {
+                            break
+                        }
+                        continue
+                    }
+                };
+                if !watch_matches(&filter, &event) {
+                    continue
                 }
-            },
+                // The client went away
+                if sender.send(Ok(event_reply(&event))).await.is_err() {
+                    break
+                }
+            }
+        });
+        Ok(Response::new(receiver))
+    }
+
+    async fn create_token(
+        &self,
+        request: Request<home_manager::CreateTokenRequest>
+    ) -> Result<Response<home_manager::CreateTokenReply>, Status> {
+        let request = request.into_inner();
+        if request.name.is_empty() {
+            return Err(Status::new(tonic::Code::InvalidArgument, "empty token name"))
         }
-        let response = Response::new(home_manager::CommandReply{reply: String::new()});
-        Ok(response)
+        let token = self.tokens.create(&request.name, role(request.role)?).map_err(auth_status)?;
+        Ok(Response::new(home_manager::CreateTokenReply{token}))
+    }
+
+    async fn revoke_token(
+        &self,
+        request: Request<home_manager::RevokeTokenRequest>
+    ) -> Result<Response<home_manager::RevokeTokenReply>, Status> {
+        let request = request.into_inner();
+        self.tokens.revoke(&request.name).map_err(auth_status)?;
+        Ok(Response::new(home_manager::RevokeTokenReply{}))
+    }
+
+    async fn list_tokens(
+        &self,
+        _request: Request<home_manager::ListTokensRequest>
+    ) -> Result<Response<home_manager::ListTokensReply>, Status> {
+        let tokens = self.tokens.list().map_err(auth_status)?;
+        Ok(Response::new(home_manager::ListTokensReply{
+            tokens: tokens
+                .into_iter()
+                .map(|info| home_manager::Token{
+                    name: info.name,
+                    role: home_manager::Role::from(info.role) as i32,
+                    created: info.created,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn get_readings(
+        &self,
+        request: Request<home_manager::GetReadingsRequest>
+    ) -> Result<Response<home_manager::GetReadingsReply>, Status> {
+        let request = request.into_inner();
+        let to = if request.to == 0 { self.clock.now().timestamp() } else { request.to };
+        if request.step > i64::max_value() as u64 {
+            return Err(Status::new(tonic::Code::InvalidArgument, "step too large"))
+        }
+        let step = request.step as i64;
+        let devices = self.devices.lock().await;
+        match devices.get(request.object_id) {
+            Ok(Some(_)) => (),
+            Ok(None) => return Err(Status::new(tonic::Code::NotFound, "device not found")),
+            Err(_) => return Err(Status::new(tonic::Code::Internal, "")),
+        }
+        // One more than returned is read to tell that the range is too large
+        let readings: Vec<_> = if step == 0 {
+            devices.readings.range(request.object_id, request.from, to, MAX_READINGS + 1)
+                .map_err(reading_status)?
+                .into_iter()
+                .map(|(time, value)| home_manager::Reading{time, value, min: value, max: value, count: 1})
+                .collect()
+        } else {
+            devices.readings.summaries(request.object_id, request.from, to, step, MAX_READINGS + 1)
+                .map_err(reading_status)?
+                .into_iter()
+                .map(|s| home_manager::Reading{time: s.time, value: s.value, min: s.min, max: s.max, count: s.count})
+                .collect()
+        };
+        if readings.len() > MAX_READINGS {
+            return Err(Status::new(tonic::Code::InvalidArgument, "too many readings, use a larger step or a shorter range"))
+        }
+        Ok(Response::new(home_manager::GetReadingsReply{readings}))
+    }
+
+    async fn record_reading(
+        &self,
+        request: Request<home_manager::RecordReadingRequest>
+    ) -> Result<Response<home_manager::RecordReadingReply>, Status> {
+        let request = request.into_inner();
+        if !request.value.is_finite() {
+            return Err(Status::new(tonic::Code::InvalidArgument, "invalid value"))
+        }
+        let time = if request.time == 0 { self.clock.now().timestamp() } else { request.time };
+        let devices = self.devices.lock().await;
+        match devices.get(request.object_id) {
+            Ok(Some(ref obj)) if obj.kind.is_sensor() => (),
+            Ok(Some(_)) => return Err(Status::new(tonic::Code::InvalidArgument, "the device is not a sensor")),
+            Ok(None) => return Err(Status::new(tonic::Code::NotFound, "device not found")),
+            Err(_) => return Err(Status::new(tonic::Code::Internal, "")),
+        }
+        devices.readings.record(request.object_id, time, request.value).map_err(reading_status)?;
+        Ok(Response::new(home_manager::RecordReadingReply{}))
+    }
+
+    async fn get_device_state(
+        &self,
+        request: Request<home_manager::GetDeviceStateRequest>
+    ) -> Result<Response<home_manager::GetDeviceStateReply>, Status> {
+        let request = request.into_inner();
+        let (obj, state) = {
+            let devices = self.devices.lock().await;
+            match (devices.get(request.object_id), devices.state(request.object_id)) {
+                (Ok(Some(obj)), Ok(state)) => (obj, state),
+                (Ok(None), _) => return Err(tonic::Status::new(tonic::Code::NotFound, "device not found")),
+                _ => return Err(tonic::Status::new(tonic::Code::Internal, "")),
+            }
+        };
+        let state = if request.refresh {
+            match Actionners::query(&self.actionners, &obj).await {
+                Ok(Some(DeviceState::Unknown)) => state,
+                Ok(Some(new_state)) => {
+                    if let Err(e) = self.devices.lock().await.set_state(request.object_id, new_state) {
+                        tracing::warn!("Could not save device state: {:?}", e);
+                    }
+                    if new_state != state {
+                        self.events.publish(events::Event::device(
+                            events::EventKind::StateChanged{object_id: request.object_id, state: new_state},
+                            &obj,
+                            0,
+                        ));
+                    }
+                    new_state
+                }
+                Ok(None) => return Err(tonic::Status::new(tonic::Code::NotFound, "actionner not found")),
+                Err(e) => {
+                    tracing::warn!("Error querying device: {:?}", e);
+                    return Err(tonic::Status::new(tonic::Code::Unavailable, "could not query device"))
+                }
+            }
+        } else {
+            state
+        };
+        Ok(Response::new(home_manager::GetDeviceStateReply{
+            state: home_manager::Status::from(state) as i32,
+        }))
     }
 }
 
 pub enum Action {}
 
-struct SshHandler;
+/// Runs commands on a remote host through the system `ssh` client.
+///
+/// Authentication is key based only: `BatchMode` is forced so that ssh never
+/// falls back to an interactive password prompt.
+struct SshHandler {
+    program: String,
+    destination: String,
+    port: Option<u16>,
+    identity: Option<std::path::PathBuf>,
+    /// In seconds
+    connect_timeout: u64,
+}
+impl SshHandler {
+    /// Parses a remote of the form `[user@]host[:port]`, an IPv6 host being
+    /// bracketed when followed by a port
+    fn from_remote(remote: &str, settings: &config::SshConfig) -> Result<SshHandler, HandlerError> {
+        let remote = remote.trim();
+        let (user, address) = match remote.rfind('@') {
+            Some(idx) => (&remote[..=idx], &remote[idx + 1..]),
+            None => ("", remote),
+        };
+        let parse_port = |port: &str| port.parse().map(Some).map_err(|_| HandlerError::InvalidAddress);
+        let (host, port) = if address.starts_with('[') {
+            let end = address.find(']').ok_or(HandlerError::InvalidAddress)?;
+            let port = match &address[end + 1..] {
+                "" => None,
+                rest if rest.starts_with(':') => parse_port(&rest[1..])?,
+                _ => return Err(HandlerError::InvalidAddress),
+            };
+            (&address[1..end], port)
+        } else {
+            match address.matches(':').count() {
+                1 => {
+                    let idx = address.find(':').unwrap();
+                    (&address[..idx], parse_port(&address[idx + 1..])?)
+                }
+                // Either no port or a bare IPv6 address
+                _ => (address, None),
+            }
+        };
+        if host.is_empty() || host.chars().any(|c| c.is_whitespace() || c == '[' || c == ']') {
+            return Err(HandlerError::InvalidAddress)
+        }
+        Ok(SshHandler {
+            program: settings.program.clone(),
+            destination: format!("{}{}", user, host),
+            port,
+            identity: settings.identity.clone(),
+            connect_timeout: settings.connect_timeout,
+        })
+    }
+    fn command(&self, remote_command: &str) -> tokio::net::process::Command {
+        let mut cmd = tokio::net::process::Command::new(&self.program);
+        cmd.arg("-o").arg("BatchMode=yes")
+            .arg("-o").arg(format!("ConnectTimeout={}", self.connect_timeout));
+        if let Some(port) = self.port {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity) = &self.identity {
+            cmd.arg("-i").arg(identity);
+        }
+        cmd.arg(&self.destination)
+            .arg("--")
+            .arg(remote_command)
+            .stdin(std::process::Stdio::null());
+        cmd
+    }
+    async fn run(&self, script: &str, args: &[String]) -> Result<std::process::Output, tokio::io::Error> {
+        let mut remote_command = script.to_owned();
+        for arg in args {
+            remote_command.push(' ');
+            remote_command.push_str(&shell_quote(arg));
+        }
+        self.command(&remote_command).output().await
+    }
+    async fn check(&self) -> Result<bool, tokio::io::Error> {
+        let status = self.command("true").status().await?;
+        Ok(status.success())
+    }
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
 struct ArduinoHandler {
-    address: String,
+    connection: arduino::Connection,
 }
 impl ArduinoHandler {
-    async fn send(&self, command: ArduinoCommand, intern_id: i8) -> Result<(), tokio::io::Error> {
-        let mut stream = tokio::net::TcpStream::connect(&self.address).await?;
-        stream.write_all(command.repr(intern_id).as_bytes()).await?;
-        Ok(())
+    /// Sends a command and returns the line the arduino answered, if any
+    async fn send(&self, command: ArduinoCommand, intern_id: i8) -> Result<String, tokio::io::Error> {
+        self.connection.send(command.repr(intern_id)).await
+    }
+    /// Asks the arduino for the state of a pin
+    async fn query(&self, intern_id: i8) -> Result<DeviceState, tokio::io::Error> {
+        let reply = self.send(ArduinoCommand::Query, intern_id).await?;
+        Ok(parse_arduino_state(&reply).unwrap_or(DeviceState::Unknown))
     }
     async fn check(&self) -> Result<bool, tokio::io::Error> {
-        let mut stream = tokio::timer::Timeout::new(tokio::net::TcpStream::connect(&self.address), std::time::Duration::from_millis(100)).await??;
-        stream.write_all(ArduinoCommand::Check.repr(0).as_bytes()).await?;
-        let mut buffer = [0; 16];
-        stream.read(&mut buffer).await?;
-        Ok(&buffer[0..3] == b"yes")
+        Ok(self.send(ArduinoCommand::Check, 0).await?.starts_with("yes"))
+    }
+}
+
+/// Parses the value sent by a sensor, binary sensors may answer like a pin
+fn parse_reading(reply: &str) -> Option<f64> {
+    match reply.trim().parse() {
+        Ok(value) => Some(value),
+        Err(_) => match parse_arduino_state(reply) {
+            Some(DeviceState::On) => Some(1.),
+            Some(DeviceState::Off) => Some(0.),
+            _ => None,
+        },
+    }
+}
+
+fn parse_arduino_state(reply: &str) -> Option<DeviceState> {
+    match reply.trim().to_lowercase().as_str() {
+        "on" | "1" | "high" => Some(DeviceState::On),
+        "off" | "0" | "low" => Some(DeviceState::Off),
+        _ => None,
     }
 }
 
@@ -364,7 +2240,12 @@ pub enum HandlerError {
     IoError(tokio::io::Error),
     Internal,
     InvalidCommand(bincode::Error),
+    /// The command does not apply to the device
+    Rejected(&'static str),
     InvalidId,
+    Offline,
+    /// The reply of a sensor is not a number
+    InvalidReading(String),
 }
 impl From<tokio::io::Error> for HandlerError {
     fn from(err: tokio::io::Error) -> Self {
@@ -377,63 +2258,357 @@ impl From<bincode::Error> for HandlerError {
     }
 }
 
-type CommandResult = ();
+pub struct CommandResult {
+    success: bool,
+    /// The state of the device after the command, `Unknown` if it can't be deduced
+    state: DeviceState,
+    /// Text sent back by the device
+    reply: String,
+}
 
 impl Handler {
-    fn protocol(&self) -> Protocol {
+    async fn new(protocol: Protocol, remote: String, settings: &ProtocolSettings) -> Result<Handler, HandlerError> {
+        let handler = match protocol {
+            Protocol::SSH => Handler::SSH(SshHandler::from_remote(&remote, &settings.ssh)?),
+            Protocol::Arduino => Handler::Arduino(ArduinoHandler{
+                connection: arduino::Connection::open(
+                    remote,
+                    settings.arduino.check_timeout(),
+                    settings.arduino.reply_timeout(),
+                ),
+            }),
+        };
+        handler.check().await?;
+        Ok(handler)
+    }
+    /// Checks that the actionner answers
+    async fn check(&self) -> Result<(), HandlerError> {
         match self {
-            Handler::Arduino(_) => Protocol::Arduino,
-            Handler::SSH(_) => Protocol::SSH,
-        }
-    }
-    async fn new(protocol: Protocol, remote: String) -> Result<Handler, HandlerError> {
-        match protocol {
-            Protocol::SSH => unimplemented!(),
-            Protocol::Arduino => {
-                let handler = ArduinoHandler{address: remote};
-                if !handler.check().await? {
-                    tracing::warn!("Arduino did not respond yes to ard request");
-                    return Err(HandlerError::Internal)
-                }
-                Ok(Handler::Arduino(handler))
-            }
+            Handler::SSH(ssh) => if !ssh.check().await? {
+                tracing::warn!("Could not run a command on {}", ssh.destination);
+                return Err(HandlerError::Internal)
+            },
+            Handler::Arduino(arduino) => if !arduino.check().await? {
+                tracing::warn!("Arduino did not respond yes to ard request");
+                return Err(HandlerError::Internal)
+            },
         }
+        Ok(())
     }
-    async fn command(&mut self, command: &[u8], object: &Object) -> Result<CommandResult, HandlerError> {
+    /// Asks the actionner for the current state of the object
+    async fn query(&self, object: &Object) -> Result<DeviceState, HandlerError> {
+        match (self, &object.id_in_actionner) {
+            (Handler::Arduino(arduino), ActionnerId::Arduino(id)) => Ok(arduino.query(*id).await?),
+            (Handler::Arduino(_), _) => Err(HandlerError::InvalidId),
+            (Handler::SSH(_), _) => Ok(DeviceState::Unknown),
+        }
+    }
+    /// Reads the current value of a sensor
+    async fn read(&self, object: &Object) -> Result<f64, HandlerError> {
+        let reply = match (self, &object.id_in_actionner) {
+            (Handler::Arduino(arduino), ActionnerId::Arduino(id)) => arduino.send(ArduinoCommand::Read, *id).await?,
+            (Handler::SSH(ssh), ActionnerId::SSH(script)) => String::from_utf8_lossy(&ssh.run(script, &[]).await?.stdout).into_owned(),
+            _ => return Err(HandlerError::InvalidId),
+        };
+        parse_reading(&reply).ok_or(HandlerError::InvalidReading(reply))
+    }
+    /// Runs a command on the object, `state` is its last known state
+    async fn command(&self, command: &[u8], object: &Object, state: DeviceState) -> Result<CommandResult, HandlerError> {
         match self {
             Handler::Arduino(arduino) => {
                 match object.id_in_actionner {
                     ActionnerId::Arduino(id) => {
                         let command: ArduinoCommand = bincode::deserialize(command)?;
-                        arduino.send(command, id).await?;
+                        command.validate(&object.kind).map_err(HandlerError::Rejected)?;
+                        let expected = match command {
+                            ArduinoCommand::Set{state: true} => Some(DeviceState::On),
+                            ArduinoCommand::Set{state: false} => Some(DeviceState::Off),
+                            ArduinoCommand::SetBrightness{level: 0} => Some(DeviceState::Off),
+                            ArduinoCommand::SetBrightness{..} => Some(DeviceState::On),
+                            ArduinoCommand::SetColor{r: 0, g: 0, b: 0} => Some(DeviceState::Off),
+                            ArduinoCommand::SetColor{..} => Some(DeviceState::On),
+                            // The arduino could have been switched by hand, only a query can tell
+                            ArduinoCommand::Toggle => None,
+                            ArduinoCommand::Check | ArduinoCommand::Query | ArduinoCommand::Read => Some(DeviceState::Unknown),
+                        };
+                        let reply = arduino.send(command, id).await?;
+                        let success = !reply.to_lowercase().starts_with("err");
+                        let state = match (parse_arduino_state(&reply), expected) {
+                            _ if !success => DeviceState::Unknown,
+                            (Some(state), _) => state,
+                            (None, Some(state)) => state,
+                            (None, None) => match arduino.query(id).await {
+                                Ok(DeviceState::Unknown) | Err(_) => state.toggled(),
+                                Ok(state) => state,
+                            },
+                        };
+                        Ok(CommandResult{success, state, reply})
                     }
-                    _ => return Err(HandlerError::InvalidId)
+                    _ => Err(HandlerError::InvalidId)
+                }
+            }
+            Handler::SSH(ssh) => {
+                match &object.id_in_actionner {
+                    ActionnerId::SSH(script) => {
+                        let SshCommand::Run{args} = bincode::deserialize(command)?;
+                        let output = ssh.run(script, &args).await?;
+                        let status = match output.status.code() {
+                            Some(code) => format!("exit status: {}", code),
+                            None => "terminated by signal".to_owned(),
+                        };
+                        Ok(CommandResult{
+                            success: output.status.success(),
+                            state: DeviceState::Unknown,
+                            reply: format!("{}\n{}", status, String::from_utf8_lossy(&output.stdout)),
+                        })
+                    }
+                    _ => Err(HandlerError::InvalidId)
                 }
             }
-            _ => unimplemented!(),
         }
-        Ok(())
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut data_dir = dirs::data_dir().expect("did not find data dir");
-    data_dir.push("home_manager");
-    let addr = "[::1]:14563".parse().unwrap();
-
-    tracing::subscriber::set_global_default(
-        tracing_subscriber::fmt::Subscriber::builder()
-            .with_max_level(tracing::Level::DEBUG)
-            .with_target(true)
-            .inherit_fields(true)
-            .finish(),
-    )
+    let args = config::Args::from_args();
+    let create_admin_token = args.create_admin_token.clone();
+    let config = Config::load(args)?;
+
+    let builder = tracing_subscriber::fmt::Subscriber::builder()
+        .with_max_level(config.log.level.level())
+        .with_target(true)
+        .inherit_fields(true);
+    match config.log.format {
+        config::LogFormat::Full => tracing::subscriber::set_global_default(builder.finish()),
+        config::LogFormat::Compact => tracing::subscriber::set_global_default(builder.compact().finish()),
+    }
     .unwrap();
 
-    let server = HomeServer::open(data_dir).await.unwrap();
-    Server::builder()
-        .serve(addr, HomeManagerServer::new(server))
-        .await?;
+    // Only the tokens are opened, the actionners are not contacted
+    if let Some(name) = create_admin_token {
+        let tokens = auth::Tokens::open(config.data_dir()).map_err(|e| format!("{:?}", e))?;
+        println!("{}", tokens.create(&name, auth::Role::Admin).map_err(|e| format!("{:?}", e))?);
+        return Ok(());
+    }
+
+    let server = HomeServer::open(&config).await.unwrap();
+    if server.tokens.list().map(|tokens| tokens.is_empty()).unwrap_or(false) {
+        tracing::warn!("No token exists, every request is refused until one is created with --create-admin-token");
+    }
+    // The server still starts on the current database, the manifest can be fixed and reloaded
+    match server.apply_manifest().await {
+        Ok(report) => {
+            for change in &report.created {
+                tracing::info!("Manifest created {}", change);
+            }
+            for change in &report.updated {
+                tracing::info!("Manifest updated {}", change);
+            }
+            for change in &report.removed {
+                tracing::info!("Manifest removed {}", change);
+            }
+        }
+        Err(e) => tracing::error!("Could not apply the manifest: {}", e),
+    }
+    let tls = match &config.tls {
+        Some(tls) => Some(tls_config(tls)?),
+        None => None,
+    };
+    let listeners = config.listen.iter().map(|addr| {
+        tracing::info!("Listening on {}", addr);
+        let tokens = server.tokens.clone();
+        let mut builder = Server::builder();
+        if let Some(tls) = &tls {
+            builder.tls_config(tls);
+        }
+        builder.interceptor_fn(move |svc, req| {
+            let path = req.uri().path().to_owned();
+            let denied = tokens.check(req.headers().get("authorization"), &path).err();
+            let fut = svc.call(req);
+            async move {
+                match denied {
+                    None => fut.await,
+                    Some(status) => {
+                        tracing::info!("Refused {}: {}", path, status.message());
+                        // The call was never polled, dropping it cancels it
+                        drop(fut);
+                        Ok(status_response(status))
+                    }
+                }
+            }
+        });
+        builder.serve(*addr, HomeManagerServer::new(server.clone()))
+    });
+    futures::future::try_join_all(listeners).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ssh(remote: &str) -> Result<SshHandler, HandlerError> {
+        SshHandler::from_remote(remote, &config::SshConfig::default())
+    }
+
+    #[test]
+    fn ssh_remotes() {
+        let cases = [
+            ("host", "host", None),
+            ("user@host", "user@host", None),
+            ("user@host:2222", "user@host", Some(2222)),
+            ("192.168.1.2:22", "192.168.1.2", Some(22)),
+            ("::1", "::1", None),
+            ("user@fe80::1", "user@fe80::1", None),
+            ("[::1]", "::1", None),
+            ("user@[::1]:22", "user@::1", Some(22)),
+            (" [fe80::1]:2222 ", "fe80::1", Some(2222)),
+        ];
+        for (remote, destination, port) in cases.iter() {
+            let handler = ssh(remote).unwrap_or_else(|e| panic!("{}: {:?}", remote, e));
+            assert_eq!(&handler.destination, destination, "{}", remote);
+            assert_eq!(&handler.port, port, "{}", remote);
+        }
+        for remote in ["", "user@", "host:port", "host:70000", "[::1", "[::1]22", "[]:22", "my host"].iter() {
+            assert!(ssh(remote).is_err(), "{} should be refused", remote);
+        }
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("plain"), "'plain'");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$(reboot); `id`"), "'$(reboot); `id`'");
+    }
+
+    /// Stands in for the ssh client, running the remote command locally
+    const FAKE_SSH: &str = "#!/bin/sh\nwhile [ \"$1\" != \"--\" ]; do shift; done\nshift\nexec sh -c \"$1\"\n";
+
+    fn fake_ssh(dir: &std::path::Path) -> SshHandler {
+        use std::os::unix::fs::PermissionsExt;
+        let program = dir.join("ssh");
+        std::fs::write(&program, FAKE_SSH).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let settings = config::SshConfig {
+            program: program.to_string_lossy().into_owned(),
+            ..config::SshConfig::default()
+        };
+        SshHandler::from_remote("user@[::1]:2222", &settings).unwrap()
+    }
+
+    #[tokio::test]
+    async fn ssh_runs_quoted_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = fake_ssh(dir.path());
+        assert!(handler.check().await.unwrap());
+        let args: Vec<String> = vec!["a  b".into(), "it's".into(), "$HOME; exit 3".into()];
+        let output = handler.run("printf '%s|'", &args).await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "a  b|it's|$HOME; exit 3|");
+    }
+
+    #[tokio::test]
+    async fn ssh_reports_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = fake_ssh(dir.path());
+        let output = handler.run("exit 3", &[]).await.unwrap();
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    /// Certificates signed by a new authority, for `localhost` on the server
+    struct Pki {
+        ca: String,
+        cert: String,
+        key: String,
+        client_cert: String,
+        client_key: String,
+    }
+
+    fn pki() -> Pki {
+        let mut ca = rcgen::CertificateParams::new(Vec::new());
+        ca.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca).unwrap();
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let client = rcgen::generate_simple_self_signed(vec!["client".to_owned()]).unwrap();
+        Pki {
+            ca: ca.serialize_pem().unwrap(),
+            cert: server.serialize_pem_with_signer(&ca).unwrap(),
+            key: server.serialize_private_key_pem(),
+            client_cert: client.serialize_pem_with_signer(&ca).unwrap(),
+            client_key: client.serialize_private_key_pem(),
+        }
+    }
+
+    /// Serves the API over TLS on a free port, requiring client certificates
+    /// signed by `client_ca` if given
+    async fn serve_tls(dir: &std::path::Path, pki: &Pki, client_ca: Option<&str>) -> u16 {
+        std::fs::write(dir.join("cert.pem"), &pki.cert).unwrap();
+        std::fs::write(dir.join("key.pem"), &pki.key).unwrap();
+        let tls = config::TlsConfig {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            client_ca: client_ca.map(|ca| {
+                std::fs::write(dir.join("client_ca.pem"), ca).unwrap();
+                dir.join("client_ca.pem")
+            }),
+        };
+        let config = Config {
+            data_dir: Some(dir.join("data")),
+            ..Config::default()
+        };
+        let server = HomeServer::open(&config).await.unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut builder = Server::builder();
+        builder.tls_config(&tls_config(&tls).unwrap());
+        let serve = builder.serve(addr, HomeManagerServer::new(server));
+        tokio::spawn(async move {
+            let _ = serve.await;
+        });
+        tokio::timer::delay_for(std::time::Duration::from_millis(100)).await;
+        addr.port()
+    }
+
+    async fn list_protocols(port: u16, ca: &str, identity: Option<(&str, &str)>) -> Result<Vec<String>, Status> {
+        use tonic::transport::{Channel, ClientTlsConfig};
+        let mut tls = ClientTlsConfig::with_rustls();
+        tls.ca_certificate(Certificate::from_pem(ca));
+        if let Some((cert, key)) = identity {
+            tls.identity(Identity::from_pem(cert, key));
+        }
+        tls.domain_name("localhost".to_owned());
+        let mut endpoint = Channel::builder(format!("https://127.0.0.1:{}", port).parse::<http::Uri>().unwrap());
+        endpoint.tls_config(&tls);
+        let mut client = home_manager::client::HomeManagerClient::new(endpoint.channel());
+        let reply = client.list_protocol(Request::new(home_manager::ListProtocolRequest{})).await?;
+        Ok(reply.into_inner().protocols.into_iter().map(|protocol| protocol.name).collect())
+    }
+
+    #[tokio::test]
+    async fn tls_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let pki = pki();
+        let port = serve_tls(dir.path(), &pki, None).await;
+        let protocols = list_protocols(port, &pki.ca, None).await.unwrap();
+        assert!(protocols.contains(&"Arduino".to_owned()));
+        // The server certificate is not signed by this authority
+        let other = pki();
+        assert!(list_protocols(port, &other.ca, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_client_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let pki = pki();
+        let port = serve_tls(dir.path(), &pki, Some(&pki.ca)).await;
+        let identity = Some((pki.client_cert.as_str(), pki.client_key.as_str()));
+        assert!(list_protocols(port, &pki.ca, identity).await.is_ok());
+        assert!(list_protocols(port, &pki.ca, None).await.is_err());
+        // Signed by an authority the server does not trust
+        let stranger = pki();
+        let identity = Some((stranger.client_cert.as_str(), stranger.client_key.as_str()));
+        assert!(list_protocols(port, &pki.ca, identity).await.is_err());
+    }
+}